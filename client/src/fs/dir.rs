@@ -1,22 +1,39 @@
 use std::{io, mem, sync::Arc};
 
-use npwire::{RMessage, Rattach, Rerror, Tattach, QTDIR};
+use bytestring::ByteString;
+use npwire::{RMessage, Rattach, Rerror, Tattach, DMDIR, OREAD, QTDIR};
 use util::fidpool::FidHandle;
 
-use super::{Filesystem, DynFilesystemInner};
+use super::{DynFilesystemInner, File, Filesystem};
 
 pub struct Directory {
     pub(super) fsys: Arc<DynFilesystemInner>,
     pub(super) fid: FidHandle
 }
 
-const MAXWELEM: usize = 16;
+pub(super) const MAXWELEM: usize = 16;
+
+/// The names to walk to get to `path`.
+pub(super) fn components(path: &str) -> Vec<ByteString> {
+    path.split('/')
+        .filter(|&c| !(c.is_empty() || c == "."))
+        .map(Into::into)
+        .collect()
+}
+
+/// Makes sure `name` names something in a directory, rather than a path.
+pub(super) fn check_name(name: &str) -> io::Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid file name"));
+    }
+    Ok(())
+}
 
 impl Filesystem {
     pub async fn attach(&self, uname: &str, aname: &str) -> io::Result<Directory> {
         let dir = Directory {
             fsys: self.fsys.clone(),
-            fid: self.fsys.get_fid().unwrap()
+            fid: self.fsys.get_fid()?
         };
 
         let resp = self.fsys.transact(Tattach {
@@ -29,6 +46,9 @@ impl Filesystem {
         match resp {
             RMessage::Rerror(Rerror { ename }) => Err(io::Error::other(&*ename)),
             RMessage::Rattach(Rattach { qid }) => {
+                if let Some(reconnect) = &self.fsys.reconnect {
+                    reconnect.attached(dir.fid.fid(), uname, aname);
+                }
                 if qid.type_ & QTDIR == QTDIR {
                     Ok(dir)
                 } else {
@@ -45,6 +65,83 @@ impl Directory {
         self.fsys.stat(&self.fid).await
     }
 
+    /// Stats whatever is at `path`, file or directory, without opening it.
+    pub async fn stat_at(&self, path: impl AsRef<str>) -> io::Result<npwire::Stat> {
+        if components(path.as_ref()).is_empty() {
+            return self.stat().await;
+        }
+
+        let (node, _) = self.walk_to(path.as_ref()).await?;
+        node.stat().await
+    }
+
+    /// Walks a new fid to `path`, which mustn't be empty, and returns it with the qid of
+    /// whatever is there. It isn't open, the `File` just holds the fid until it's clunked.
+    pub(super) async fn walk_to(&self, path: &str) -> io::Result<(File, npwire::Qid)> {
+        let wname = components(path);
+
+        if wname.len() > MAXWELEM {
+            return Err(io::Error::other("path too deep"));
+        }
+
+        let node = File {
+            fsys: self.fsys.clone(),
+            fid: self.fsys.get_fid()?
+        };
+
+        let nc = wname.len();
+        let wqid = self.fsys.walk(&self.fid, &node.fid, wname).await?;
+
+        if wqid.len() < nc {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+
+        if wqid.len() > nc {
+            return Err(io::Error::other("invalid response from server"));
+        }
+
+        let qid = *wqid.last().ok_or(io::ErrorKind::InvalidInput)?;
+        Ok((node, qid))
+    }
+
+    /// Creates `name` in this directory and opens it with `mode`.
+    pub(super) async fn create(&self, name: &str, perm: u32, mode: u8) -> io::Result<File> {
+        check_name(name)?;
+
+        // Tcreate turns the fid into the new file's, so it gets a copy of ours
+        let file = File {
+            fsys: self.fsys.clone(),
+            fid: self.fsys.get_fid()?
+        };
+        self.fsys.walk(&self.fid, &file.fid, Vec::new()).await?;
+        self.fsys.create(&file.fid, name, perm, mode).await?;
+
+        Ok(file)
+    }
+
+    /// Creates the directory `name` in this one with permissions `perm`.
+    pub async fn create_dir(&self, name: &str, perm: u32) -> io::Result<Self> {
+        drop(self.create(name, perm | DMDIR, OREAD).await?);
+        self.open_dir_at(name).await
+    }
+
+    /// Removes this directory from the server, which generally wants it empty first.
+    pub async fn remove(mut self) -> io::Result<()> {
+        let fid = mem::take(&mut self.fid);
+        self.fsys.remove(fid).await
+    }
+
+    /// Removes whatever is at `path`, file or directory.
+    pub async fn remove_at(&self, path: impl AsRef<str>) -> io::Result<()> {
+        if components(path.as_ref()).is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "can't remove the directory itself"));
+        }
+
+        let (mut node, _) = self.walk_to(path.as_ref()).await?;
+        let fid = mem::take(&mut node.fid);
+        self.fsys.remove(fid).await
+    }
+
     pub async fn try_clone(&self) -> io::Result<Self> {
         self.open_dir_at("").await
     }
@@ -52,14 +149,10 @@ impl Directory {
     pub async fn open_dir_at(&self, path: impl AsRef<str>) -> io::Result<Self> {
         let dir = Directory {
             fsys: self.fsys.clone(),
-            fid: self.fsys.get_fid().unwrap()
+            fid: self.fsys.get_fid()?
         };
 
-        let mut wname = path.as_ref()
-            .split('/')
-            .filter(|&c| !(c.is_empty() || c == "."))
-            .map(Into::into)
-            .collect::<Vec<_>>();
+        let mut wname = components(path.as_ref());
 
         let mut wqid = Vec::new();
 
@@ -98,6 +191,11 @@ impl Directory {
 
 impl Drop for Directory {
     fn drop(&mut self) {
+        // Already clunked, by remove
+        if self.fid.is_nofid() {
+            return;
+        }
+
         let fsys = self.fsys.clone();
         let fid = mem::take(&mut self.fid);
         
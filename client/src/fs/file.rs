@@ -1,61 +1,107 @@
-use std::{future::pending, io, mem, pin::Pin, sync::Arc, task::{ready, Context, Poll}};
+use std::{io, mem, pin::Pin, sync::Arc, task::{ready, Context, Poll}};
 
-use bytes::{Buf as _, Bytes};
-use npwire::{RMessage, Rerror, Rread, Rwrite, Tread, Twrite, QTDIR};
+use bytes::{Buf as _, Bytes, BytesMut};
+use futures::{future::BoxFuture, stream::{FuturesOrdered, FuturesUnordered}, StreamExt as _};
+use npwire::{RMessage, Rerror, Rread, Rwrite, Tread, Twrite, DMDIR, OREAD, ORCLOSE, ORDWR, OTRUNC, OWRITE, QTDIR};
 use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
-use tokio_util::sync::ReusableBoxFuture;
 use util::fidpool::FidHandle;
 
 
-use super::{Directory, DynFilesystemInner};
+use super::{dir::components, Directory, DynFilesystemInner};
 
 pub struct File {
     pub(super) fsys: Arc<DynFilesystemInner>,
     pub(super) fid: FidHandle
 }
 
-impl Directory {
-    pub async fn open_at(&self, path: impl AsRef<str>) -> io::Result<File> {
-        let file = File {
-            fsys: self.fsys.clone(),
-            fid: self.fsys.get_fid().unwrap()
-        };
+/// How to open a file, like [`std::fs::OpenOptions`]. Reading and writing together
+/// is 9P's `ORDWR`.
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    truncate: bool,
+    remove_on_close: bool
+}
 
-        let wname = path.as_ref()
-            .split('/')
-            .filter(|&c| !(c.is_empty() || c == "."))
-            .map(Into::into)
-            .collect::<Vec<_>>();
+impl OpenOptions {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        if wname.is_empty() {
-            return Err(io::ErrorKind::IsADirectory.into());
-        }
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
 
-        let nc = wname.len();
+    /// Truncates the file to nothing on open. Needs write access.
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
 
-        let wqid = self.fsys.walk(
-            &self.fid,
-            &file.fid,
-            wname).await?;
+    /// Has the server remove the file once it's closed, `ORCLOSE`.
+    pub fn remove_on_close(&mut self, remove_on_close: bool) -> &mut Self {
+        self.remove_on_close = remove_on_close;
+        self
+    }
 
-        if wqid.len() < nc {
-            return Err(io::ErrorKind::NotFound.into());
+    pub(super) fn mode(&self) -> io::Result<u8> {
+        let mut mode = match (self.read, self.write) {
+            (true, false) => OREAD,
+            (false, true) => OWRITE,
+            (true, true) => ORDWR,
+            (false, false) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "neither read nor write access"))
+        };
+        if self.truncate {
+            if !self.write {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "truncating needs write access"));
+            }
+            mode |= OTRUNC;
         }
-        
-        if wqid.len() > nc {
-            return Err(io::Error::other("invalid response from server"));
+        if self.remove_on_close {
+            mode |= ORCLOSE;
+        }
+        Ok(mode)
+    }
+}
+
+impl Directory {
+    pub async fn open_at(&self, path: impl AsRef<str>) -> io::Result<File> {
+        self.open_with(path, OpenOptions::new().read(true)).await
+    }
+
+    pub async fn open_with(&self, path: impl AsRef<str>, options: &OpenOptions) -> io::Result<File> {
+        let mode = options.mode()?;
+
+        if components(path.as_ref()).is_empty() {
+            return Err(io::ErrorKind::IsADirectory.into());
         }
 
-        if wqid.last().unwrap().type_ & QTDIR == QTDIR {
+        let (file, wqid) = self.walk_to(path.as_ref()).await?;
+
+        if wqid.type_ & QTDIR == QTDIR {
             return Err(io::ErrorKind::IsADirectory.into());
         }
 
-        let qid = self.fsys.open(&file.fid).await?;
+        let qid = self.fsys.open(&file.fid, mode).await?;
 
-        assert_eq!(wqid.last(), Some(&qid));
+        assert_eq!(wqid.path, qid.path);
 
         Ok(file)
     }
+
+    /// Creates the file `name` in this directory with permissions `perm`, and opens it.
+    pub async fn create_file(&self, name: &str, perm: u32, options: &OpenOptions) -> io::Result<File> {
+        let mode = options.mode()?;
+        self.create(name, perm & !DMDIR, mode).await
+    }
 }
 
 impl File {
@@ -63,6 +109,18 @@ impl File {
         self.fsys.stat(&self.fid).await
     }
 
+    /// Removes the file from the server, closing it either way.
+    pub async fn remove(mut self) -> io::Result<()> {
+        let fid = mem::take(&mut self.fid);
+        self.fsys.remove(fid).await
+    }
+
+    /// The most one [`File::read_at`] can return, however big its `count`.
+    #[must_use]
+    pub fn read_size(&self) -> u32 {
+        self.fsys.read_size()
+    }
+
     pub async fn read_at(&self, count: u32, offset: u64) -> io::Result<Bytes> {
         let resp = self.fsys.transact(Tread {
             fid: self.fid.fid(),
@@ -94,6 +152,11 @@ impl File {
 
 impl Drop for File {
     fn drop(&mut self) {
+        // Already clunked, by remove
+        if self.fid.is_nofid() {
+            return;
+        }
+
         let fsys = self.fsys.clone();
         let fid = mem::take(&mut self.fid);
         
@@ -103,23 +166,63 @@ impl Drop for File {
     }
 }
 
+/// How many Treads a [`FileReader`] keeps out at once, unless told otherwise.
+pub const DEFAULT_READ_AHEAD: usize = 8;
+
+/// Reads a file in order, with a few Treads at the offsets after the one being read
+/// already on their way. A reply's worth a round trip either way, so it starts with
+/// one, and doubles how many it keeps out after every full reply. A short reply means
+/// the ones after it were at the wrong offsets, so those are dropped, which flushes
+/// them, and so does seeking.
 pub struct FileReader<'a> {
     file: &'a File,
     offset: u64,
-    fut_valid: bool,
-    fut: ReusableBoxFuture<'a, io::Result<Bytes>>
+    /// Read, but not read out yet. Starts at `offset`.
+    buffer: Bytes,
+    /// Where the next Tread goes
+    next: u64,
+    window: usize,
+    read_ahead: usize,
+    reads: FuturesOrdered<BoxFuture<'a, (u32, io::Result<Bytes>)>>
 }
 
 impl<'a> FileReader<'a> {
     #[must_use]
     pub fn new(file: &'a File) -> Self {
+        Self::with_read_ahead(file, DEFAULT_READ_AHEAD)
+    }
+
+    /// Like [`FileReader::new`], but with up to `read_ahead` Treads out at once.
+    /// A `read_ahead` of 1 reads one reply's worth at a time.
+    #[must_use]
+    pub fn with_read_ahead(file: &'a File, read_ahead: usize) -> Self {
         Self {
             file,
             offset: 0,
-            fut_valid: false,
-            fut: ReusableBoxFuture::new(pending())
+            buffer: Bytes::new(),
+            next: 0,
+            window: 1,
+            read_ahead: read_ahead.max(1),
+            reads: FuturesOrdered::new()
+        }
+    }
+
+    /// Sends Treads until there's a window's worth out.
+    fn fill(&mut self) {
+        let count = self.file.fsys.read_size();
+        while self.reads.len() < self.window {
+            let (file, offset) = (self.file, self.next);
+            self.reads.push_back(Box::pin(async move { (count, file.read_at(count, offset).await) }));
+            self.next += u64::from(count);
         }
     }
+
+    /// Gives up on the reads still out, and starts over after what's buffered.
+    fn discard(&mut self) {
+        self.reads = FuturesOrdered::new();
+        self.next = self.offset + self.buffer.len() as u64;
+        self.window = 1;
+    }
 }
 
 impl AsyncRead for FileReader<'_> {
@@ -132,18 +235,30 @@ impl AsyncRead for FileReader<'_> {
             return Poll::Ready(Ok(()));
         }
 
-        if !self.fut_valid {
-            let count = buf.remaining().try_into().unwrap_or(u32::MAX);
-            let fut = self.file.read_at(count, self.offset);
-            self.fut.set(fut);
-            self.fut_valid = true;
+        if self.buffer.is_empty() {
+            self.fill();
+            let (count, res) = ready!(self.reads.poll_next_unpin(cx)).expect("a read is always out here");
+            let data = match res {
+                Ok(data) => data,
+                Err(e) => {
+                    self.discard();
+                    return Poll::Ready(Err(e));
+                }
+            };
+            self.buffer = data;
+
+            if self.buffer.len() < count as usize {
+                // Nothing at all is the end of the file, for now
+                self.discard();
+            } else {
+                self.window = (self.window * 2).min(self.read_ahead);
+            }
         }
 
-        let res = ready!(self.fut.poll(cx))?;
-        self.fut_valid = false;
-        let n = res.len().min(buf.remaining());
-        buf.put_slice(&res[..n]);
-        self.offset += u64::try_from(n).map_err(io::Error::other)?;
+        let n = self.buffer.len().min(buf.remaining());
+        buf.put_slice(&self.buffer[..n]);
+        self.buffer.advance(n);
+        self.offset += n as u64;
 
         Poll::Ready(Ok(()))
     }
@@ -151,26 +266,19 @@ impl AsyncRead for FileReader<'_> {
 
 impl AsyncSeek for FileReader<'_> {
     fn start_seek(mut self: Pin<&mut Self>, position: io::SeekFrom) -> io::Result<()> {
-        match position {
-            io::SeekFrom::Start(offset) => {
-                if offset != self.offset {
-                    self.fut_valid = false;
-                }
-                self.offset = offset;
-                Ok(())
-            },
-            io::SeekFrom::Current(offset) => {
-                let offset = self.offset.checked_add_signed(offset).ok_or(io::ErrorKind::InvalidInput)?;
-                if offset != self.offset {
-                    self.fut_valid = false;
-                }
-                self.offset = offset;
-                Ok(())
-            },
+        let offset = match position {
+            io::SeekFrom::Start(offset) => offset,
+            io::SeekFrom::Current(offset) => self.offset.checked_add_signed(offset).ok_or(io::ErrorKind::InvalidInput)?,
             io::SeekFrom::End(_) => {
                 todo!()
             }
+        };
+        if offset != self.offset {
+            self.offset = offset;
+            self.buffer.clear();
+            self.discard();
         }
+        Ok(())
     }
 
     fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
@@ -178,25 +286,108 @@ impl AsyncSeek for FileReader<'_> {
     }
 }
 
+/// How many Twrites a [`FileWriter`] keeps out at once, unless told otherwise.
+pub const DEFAULT_WRITE_BEHIND: usize = 8;
+
+type PendingWrite<'a> = BoxFuture<'a, (u64, Bytes, io::Result<u32>)>;
+
+/// Writes a file in order, a Twrite's worth at a time, without waiting for one to be
+/// answered before sending the next. Whatever the server doesn't take of one is sent
+/// again. Since a write can fail after it's returned, the first failure is returned
+/// from every call after it, flushing and shutting down included. Anything not yet
+/// flushed when this is dropped may or may not be written.
 pub struct FileWriter<'a> {
     file: &'a File,
+    /// Just past what's been written to this
     offset: u64,
-    buffer: Bytes,
-    fut_valid: bool,
-    fut: ReusableBoxFuture<'a, io::Result<u32>>
+    /// Not sent yet. Ends at `offset`.
+    buffer: BytesMut,
+    write_behind: usize,
+    writes: FuturesUnordered<PendingWrite<'a>>,
+    error: Option<io::Error>
 }
 
 impl<'a> FileWriter<'a> {
     #[must_use]
     pub fn new(file: &'a File) -> Self {
+        Self::with_write_behind(file, DEFAULT_WRITE_BEHIND)
+    }
+
+    /// Like [`FileWriter::new`], but with up to `write_behind` Twrites out at once.
+    /// A `write_behind` of 1 waits for each write before sending the next.
+    #[must_use]
+    pub fn with_write_behind(file: &'a File, write_behind: usize) -> Self {
         Self {
             file,
             offset: 0,
-            buffer: Bytes::new(),
-            fut_valid: false,
-            fut: ReusableBoxFuture::new(pending())
+            buffer: BytesMut::new(),
+            write_behind: write_behind.max(1),
+            writes: FuturesUnordered::new(),
+            error: None
         }
     }
+
+    fn failed(&self) -> io::Result<()> {
+        match &self.error {
+            Some(e) => Err(io::Error::new(e.kind(), e.to_string())),
+            None => Ok(())
+        }
+    }
+
+    fn write(&mut self, data: Bytes, offset: u64) {
+        let file = self.file;
+        self.writes.push(Box::pin(async move {
+            let res = file.write_at(data.clone(), offset).await;
+            (offset, data, res)
+        }));
+    }
+
+    /// Sends what's buffered.
+    fn send(&mut self) {
+        let data = self.buffer.split().freeze();
+        let offset = self.offset - data.len() as u64;
+        self.write(data, offset);
+    }
+
+    /// Waits for a write to be answered, sending the rest of it again if it was short.
+    /// `None` if there were none out.
+    fn poll_write_done(&mut self, cx: &mut Context<'_>) -> Poll<Option<io::Result<()>>> {
+        let Some((offset, mut data, res)) = ready!(self.writes.poll_next_unpin(cx)) else {
+            return Poll::Ready(None);
+        };
+        let res = res.and_then(|n| match n as usize {
+            0 => Err(io::Error::from(io::ErrorKind::WriteZero)),
+            n if n > data.len() => Err(io::Error::other("wrote more than was sent")),
+            n => Ok(n)
+        });
+
+        match res {
+            Ok(n) => {
+                if n < data.len() {
+                    data.advance(n);
+                    self.write(data, offset + n as u64);
+                }
+                Poll::Ready(Some(Ok(())))
+            },
+            Err(e) => {
+                // Nothing after this is any use, with a gap before it
+                self.writes = FuturesUnordered::new();
+                self.buffer.clear();
+                let ret = io::Error::new(e.kind(), e.to_string());
+                self.error = Some(e);
+                Poll::Ready(Some(Err(ret)))
+            }
+        }
+    }
+
+    /// Moves the writes that are out along until none can go further without waiting,
+    /// which is what sends new ones.
+    fn poll_writes(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        while let Poll::Ready(Some(res)) = self.poll_write_done(cx) {
+            res?;
+        }
+        Ok(())
+    }
 }
 
 impl AsyncWrite for FileWriter<'_> {
@@ -205,26 +396,44 @@ impl AsyncWrite for FileWriter<'_> {
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
-        ready!(self.as_mut().poll_flush(cx))?;
-        self.buffer = Bytes::copy_from_slice(buf);
-        Poll::Ready(Ok(buf.len()))
+        self.failed()?;
+        self.poll_writes(cx)?;
+
+        let size = self.file.fsys.write_size();
+        while self.buffer.len() >= size {
+            if self.writes.len() < self.write_behind {
+                self.send();
+                break;
+            }
+            if let Some(res) = ready!(self.poll_write_done(cx)) {
+                res?;
+            }
+        }
+
+        let n = buf.len().min(size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        self.offset += n as u64;
+        if self.buffer.len() == size && self.writes.len() < self.write_behind {
+            self.send();
+        }
+        // Out now rather than at the next flush. If that fails, the next call says so.
+        let _ = self.poll_writes(cx);
+
+        Poll::Ready(Ok(n))
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        while !self.buffer.is_empty() {
-            if !self.fut_valid {
-                let fut = self.file.write_at(self.buffer.clone(), self.offset);
-                self.fut.set(fut);
-                self.fut_valid = true;
+        self.failed()?;
+
+        loop {
+            if !self.buffer.is_empty() && self.writes.len() < self.write_behind {
+                self.send();
+            }
+            match ready!(self.poll_write_done(cx)) {
+                Some(res) => res?,
+                None => return Poll::Ready(Ok(()))
             }
-    
-            let res = ready!(self.fut.poll(cx))?;
-            self.fut_valid = false;
-            self.buffer.advance(res.try_into().map_err(io::Error::other)?);
-            self.offset += u64::from(res);
         }
-
-        Poll::Ready(Ok(()))
     }
 
     fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
@@ -234,7 +443,7 @@ impl AsyncWrite for FileWriter<'_> {
 
 impl AsyncSeek for FileWriter<'_> {
     fn start_seek(mut self: Pin<&mut Self>, position: io::SeekFrom) -> io::Result<()> {
-        if !self.buffer.is_empty() || self.fut_valid {
+        if !self.buffer.is_empty() || !self.writes.is_empty() {
             return Err(io::ErrorKind::Other.into());
         }
 
@@ -1,25 +1,35 @@
-use std::{collections::BTreeMap, io, mem, sync::Arc};
+use std::{collections::BTreeMap, future::Future, io, mem, sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Arc}};
 
 use async_trait::async_trait;
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use bytestring::ByteString;
-use npwire::{deserialize_r, RMessage, TMessage, Tversion};
+use npwire::{deserialize_r, RMessage, TMessage, Tversion, RREAD_OVERHEAD, TWRITE_OVERHEAD};
 use parking_lot::Mutex;
 
 mod transact;
 mod dir;
 mod file;
 mod readdir;
+mod reconnect;
+mod wstat;
 
 pub use dir::*;
 pub use readdir::*;
 pub use file::*;
-use tokio::sync::oneshot;
-use tracing::trace;
+pub use reconnect::RECONNECT_TIMEOUT;
+pub use wstat::*;
+use reconnect::Reconnect;
+use tokio::sync::{mpsc, oneshot, watch, OwnedSemaphorePermit, Semaphore};
+use tracing::{error, trace, warn};
 use util::fidpool::{FidHandle, FidPool};
 
 const MAX_MESSAGE_SIZE: u32 = 1280 - 64 - 8 - 16;
 
+/// The server works on this many requests at once. More than that just wait in line.
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 16;
+// Every tag but NOTAG
+const MAX_TAGS: usize = u16::MAX as usize;
+
 #[async_trait]
 pub trait Transport {
     async fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
@@ -41,11 +51,82 @@ impl Transport for transport::SecureTransport {
     }
 }
 
-// todo: AtomicBool flag in case recv task dies
+/// Where a tag's reply goes, and the slot it takes up while it's out.
+enum Inflight {
+    Waiting {
+        reply_to: oneshot::Sender<io::Result<RMessage>>,
+        slot: Slot,
+        /// The request as sent, if it's safe to send again on a new connection
+        replay: Option<Bytes>,
+        newfid: Option<NewFid>
+    },
+    /// Given up on, with a Tflush sent or about to be. The tag can't be reused until
+    /// the Rflush.
+    Flushing {
+        slot: Slot,
+        newfid: Option<NewFid>,
+        /// Whether a reply beat the Rflush and made `newfid` after all
+        made: bool,
+        /// `newfid`, if it was clunked meanwhile. It's held until the Rflush, since
+        /// until then there's no telling whether the server has it.
+        held: Option<FidHandle>
+    }
+}
+
+/// The fid a Tattach or a Twalk to a new fid makes, if it works.
+#[derive(Debug, Clone, Copy)]
+struct NewFid {
+    fid: u32,
+    /// How many names a walk has to get through to make it
+    nwname: usize
+}
+
+impl NewFid {
+    fn of(message: &TMessage) -> Option<Self> {
+        match message {
+            TMessage::Tattach(m) => Some(Self { fid: m.fid, nwname: 0 }),
+            TMessage::Twalk(m) if m.newfid != m.fid => Some(Self { fid: m.newfid, nwname: m.wname.len() }),
+            _ => None
+        }
+    }
+
+    fn made_by(&self, resp: &RMessage) -> bool {
+        match resp {
+            RMessage::Rattach(_) => true,
+            RMessage::Rwalk(walk) => walk.wqid.len() == self.nwname,
+            _ => false
+        }
+    }
+}
+
+/// A request's place among the ones in flight. Tflushes go without, since they're what
+/// frees places up.
+type Slot = Option<OwnedSemaphorePermit>;
+
+/// Whether the connection to the server is still up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Health {
+    Connected,
+    /// Lost, and being made again (see [`Filesystem::resilient`]). New requests wait
+    /// until it's back.
+    Reconnecting,
+    /// Lost, for the given reason. Every request fails with
+    /// [`ConnectionAborted`](io::ErrorKind::ConnectionAborted) from then on.
+    Closed(String)
+}
+
 pub(crate) struct FilesystemInner<T: ?Sized> {
-    inflight: Mutex<BTreeMap<u16, oneshot::Sender<RMessage>>>,
+    inflight: Mutex<BTreeMap<u16, Inflight>>,
+    /// Only ever changed with `inflight` locked, so nothing new goes in after it's emptied
+    health: watch::Sender<Health>,
+    /// Tags to flush, for the flush task
+    flushes: mpsc::UnboundedSender<u16>,
+    slots: Arc<Semaphore>,
     fids: FidPool,
-    maxlen: usize,
+    maxlen: AtomicUsize,
+    /// How many times we've reconnected
+    generation: AtomicU64,
+    reconnect: Option<Reconnect>,
     transport: T
 }
 
@@ -56,73 +137,697 @@ pub struct Filesystem {
 }
 
 impl<T: ?Sized> FilesystemInner<T> {
-    fn get_fid(&self) -> Option<FidHandle> {
-        self.fids.get()
+    fn get_fid(&self) -> io::Result<FidHandle> {
+        self.fids.get().ok_or_else(|| io::Error::other("out of fids"))
+    }
+
+    /// Fails everything waiting on a reply or a slot, and everything after.
+    fn close(&self, reason: String) {
+        let mut inflight = self.inflight.lock();
+        self.health.send_replace(Health::Closed(reason));
+        inflight.clear();
+        self.slots.close();
+    }
+
+    fn aborted(&self) -> io::Error {
+        match &*self.health.borrow() {
+            Health::Closed(reason) => io::Error::new(io::ErrorKind::ConnectionAborted, reason.clone()),
+            _ => io::ErrorKind::ConnectionAborted.into()
+        }
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// The most a single Tread can get back.
+    fn read_size(&self) -> u32 {
+        (self.maxlen.load(Ordering::Acquire) - RREAD_OVERHEAD).try_into().unwrap_or(u32::MAX)
+    }
+
+    /// The most a single Twrite can carry.
+    fn write_size(&self) -> usize {
+        self.maxlen.load(Ordering::Acquire) - TWRITE_OVERHEAD
+    }
+}
+
+impl DynFilesystemInner {
+    /// Hands out replies until the transport fails, with whatever it failed with.
+    async fn receive(&self) -> io::Error {
+        let mut resp = BytesMut::zeroed(MAX_MESSAGE_SIZE as usize);
+        loop {
+            let n = match self.transport.recv(&mut resp).await {
+                Ok(n) => n,
+                Err(e) => return e
+            };
+            let mut resp = mem::replace(&mut resp, BytesMut::zeroed(MAX_MESSAGE_SIZE as usize));
+            resp.truncate(n);
+            if let Ok((tag, resp)) = deserialize_r(resp.freeze()) {
+                trace!("received reply with tag {tag}, {resp:?}");
+
+                let mut inflight = self.inflight.lock();
+                match inflight.remove(&tag) {
+                    Some(Inflight::Waiting { reply_to, .. }) => { let _ = reply_to.send(Ok(resp)); },
+                    // A reply beat the Rflush. The server's done, but the tag's ours
+                    // until the Rflush all the same, and any fid it made is clunked then.
+                    Some(Inflight::Flushing { slot, newfid, held, .. }) => {
+                        trace!("reply to flushed request {tag} came first");
+                        let made = newfid.is_some_and(|newfid| newfid.made_by(&resp));
+                        inflight.insert(tag, Inflight::Flushing { slot, newfid, made, held });
+                    },
+                    None => ()
+                }
+            }
+        }
+    }
+
+    /// Receives for as long as there's a connection, reconnecting if we're meant to.
+    async fn supervise(self: Arc<Self>) {
+        loop {
+            let receiver = tokio::spawn({
+                let inner = self.clone();
+                async move { inner.receive().await }
+            });
+            let reason = match receiver.await {
+                Ok(e) => e.to_string(),
+                Err(e) => e.to_string()
+            };
+
+            let Some(reconnect) = &self.reconnect else {
+                error!("fatal error: {reason}");
+                self.close(reason);
+                return;
+            };
+
+            warn!("lost the connection ({reason}), reconnecting");
+            let replay = self.suspend();
+            match reconnect.connect().await {
+                Ok(maxlen) => self.maxlen.store(maxlen, Ordering::Release),
+                Err(e) => {
+                    error!("fatal error: {reason}, and couldn't reconnect: {e}");
+                    self.close(format!("{reason}, and couldn't reconnect: {e}"));
+                    return;
+                }
+            }
+
+            // Fids go back while we receive their replies
+            tokio::spawn({
+                let inner = self.clone();
+                let generation = self.generation();
+                async move { inner.restore(generation, replay).await }
+            });
+        }
+    }
+}
+
+/// Agrees on a version and message size with the server, returning the size.
+async fn version<T: Transport + ?Sized>(transport: &T) -> io::Result<usize> {
+    let ver = TMessage::Tversion(Tversion {
+        msize: MAX_MESSAGE_SIZE,
+        version: ByteString::from_static("9P2000")
+    });
+    transport.send(&ver.serialize(!0).unwrap()).await?;
+    trace!("sent request {ver:?}");
+
+    let mut ver = BytesMut::zeroed(MAX_MESSAGE_SIZE as usize);
+    let n = transport.recv(&mut ver).await?;
+    ver.truncate(n);
+
+    let (_, ver) = deserialize_r(ver.freeze()).map_err(io::Error::other)?;
+    trace!("received reply {ver:?}");
+    let RMessage::Rversion(ver) = ver else {
+        return Err(io::Error::other("invalid version response"))
+    };
+
+    if ver.version != "9P2000" {
+        return Err(io::Error::other("protocol not supported"))
     }
+    Ok(ver.msize as usize)
 }
 
 impl Filesystem {
     pub async fn new(transport: impl Transport + Send + Sync + 'static) -> io::Result<Self> {
-        let mut inner = FilesystemInner {
+        Self::with_max_in_flight(transport, DEFAULT_MAX_IN_FLIGHT).await
+    }
+
+    /// Like [`Filesystem::new`], but with up to `max_in_flight` requests out at once.
+    /// Any more wait until one's answered.
+    pub async fn with_max_in_flight(transport: impl Transport + Send + Sync + 'static, max_in_flight: usize) -> io::Result<Self> {
+        let maxlen = version(&transport).await?;
+        Ok(Self::start(transport, maxlen, max_in_flight, None))
+    }
+
+    /// Connects with `connect`, and whenever the connection drops, connects again the
+    /// same way and puts every fid back as it was: attached with the same names, walked
+    /// to the same path and opened the same way, but not truncated again. Reads and
+    /// writes pick up at the same offsets, since 9P gives every one of them its own.
+    ///
+    /// Until that's done, new requests wait. Requests that were waiting on a reply when
+    /// the connection dropped are sent again if that's safe. Ones that change things,
+    /// like writes and removes, fail with [`ConnectionReset`](io::ErrorKind::ConnectionReset)
+    /// instead, since there's no telling whether they happened. Fids that can't be put
+    /// back, say because their file's gone, fail everything but being clunked.
+    ///
+    /// After trying for [`RECONNECT_TIMEOUT`], the connection is closed for good.
+    pub async fn resilient<F, Fut, T>(connect: F) -> io::Result<Self>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<T>> + Send + 'static,
+        T: Transport + Send + Sync + 'static
+    {
+        Self::resilient_with_max_in_flight(connect, DEFAULT_MAX_IN_FLIGHT).await
+    }
+
+    /// Like [`Filesystem::resilient`], with `max_in_flight` as for
+    /// [`Filesystem::with_max_in_flight`].
+    pub async fn resilient_with_max_in_flight<F, Fut, T>(connect: F, max_in_flight: usize) -> io::Result<Self>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<T>> + Send + 'static,
+        T: Transport + Send + Sync + 'static
+    {
+        let (reconnect, transport, maxlen) = Reconnect::new(connect).await?;
+        Ok(Self::start(transport, maxlen, max_in_flight, Some(reconnect)))
+    }
+
+    fn start(transport: impl Transport + Send + Sync + 'static, maxlen: usize, max_in_flight: usize, reconnect: Option<Reconnect>) -> Self {
+        let (flushes, mut to_flush) = mpsc::unbounded_channel();
+        let inner: Arc<DynFilesystemInner> = Arc::new(FilesystemInner {
             transport,
             inflight: Default::default(),
+            health: watch::Sender::new(Health::Connected),
+            flushes,
+            // Leaving a tag for every request's Tflush
+            slots: Arc::new(Semaphore::new(max_in_flight.clamp(1, MAX_TAGS / 2))),
             fids: FidPool::new(),
-            maxlen: 0
-        };
+            maxlen: AtomicUsize::new(maxlen),
+            generation: AtomicU64::new(0),
+            reconnect
+        });
+
+        tokio::spawn(inner.clone().supervise());
 
-        let ver = TMessage::Tversion(Tversion {
-            msize: MAX_MESSAGE_SIZE,
-            version: ByteString::from_static("9P2000")
+        // Requests given up on are flushed from here, since dropping them can't
+        let inner2 = inner.clone();
+        tokio::spawn(async move {
+            while let Some(oldtag) = to_flush.recv().await {
+                let inner = inner2.clone();
+                tokio::spawn(async move { inner.flush(oldtag).await });
+            }
         });
-        inner.transport.send(&ver.serialize(!0).unwrap()).await?;
-        trace!("sent request {ver:?}");
-
-        let mut ver = BytesMut::zeroed(MAX_MESSAGE_SIZE as usize);
-        let n = inner.transport.recv(&mut ver).await?;
-        ver.truncate(n);
-        
-        let (_, ver) = deserialize_r(ver.freeze()).map_err(io::Error::other)?;
-        trace!("received reply {ver:?}");
-        let RMessage::Rversion(ver) = ver else {
-            return Err(io::Error::other("invalid version response"))
-        };
 
-        if ver.version != "9P2000" {
-            return Err(io::Error::other("protocol not supported"))
+        Self {
+            fsys: inner
         }
-        inner.maxlen = ver.msize as usize;
+    }
 
-        let inner = Arc::new(inner);
-        let inner2 = inner.clone();
+    pub fn health(&self) -> Health {
+        self.fsys.health.borrow().clone()
+    }
+
+    /// Resolves once the connection is lost for good.
+    pub async fn closed(&self) {
+        let _ = self.fsys.health.subscribe().wait_for(|health| matches!(health, Health::Closed(_))).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use npwire::{
+        deserialize_t, Qid, Rattach, Rclunk, Rerror, Rflush, Ropen, Rread, Rversion, Rwalk, Rwrite, Tattach,
+        Tclunk, Tflush, Topen, Tread, Twalk, Twrite, QTDIR, QTFILE
+    };
+    use tokio::{io::{AsyncReadExt as _, AsyncSeekExt as _, AsyncWriteExt as _}, sync::Mutex as AsyncMutex, time};
+
+    use super::*;
+
+    const DIR: Qid = Qid { type_: QTDIR, version: 0, path: 1 };
+    const FILE: Qid = Qid { type_: QTFILE, version: 0, path: 2 };
+
+    /// What's at `offset` onwards in the files the tests read and write
+    fn pattern(offset: u64, len: usize) -> Vec<u8> {
+        (offset..offset + len as u64).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Hands every request to the test, and replies with whatever it says to.
+    struct Scripted {
+        sent: mpsc::UnboundedSender<(u16, TMessage)>,
+        replies: AsyncMutex<mpsc::UnboundedReceiver<Bytes>>
+    }
+
+    #[async_trait]
+    impl Transport for Scripted {
+        async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+            let reply = self.replies.lock().await.recv().await.ok_or(io::ErrorKind::BrokenPipe)?;
+            buf[..reply.len()].copy_from_slice(&reply);
+            Ok(reply.len())
+        }
+
+        async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+            let req = deserialize_t(Bytes::copy_from_slice(buf)).map_err(io::Error::other)?;
+            self.sent.send(req).map_err(|_| io::ErrorKind::BrokenPipe)?;
+            Ok(buf.len())
+        }
+    }
+
+    /// The test's end of a [`Scripted`] transport. Dropping it drops the connection.
+    struct Script {
+        sent: mpsc::UnboundedReceiver<(u16, TMessage)>,
+        replies: mpsc::UnboundedSender<Bytes>
+    }
+
+    fn scripted() -> (Scripted, Script) {
+        let (sent_tx, sent) = mpsc::unbounded_channel();
+        let (replies, replies_rx) = mpsc::unbounded_channel();
+        (Scripted { sent: sent_tx, replies: AsyncMutex::new(replies_rx) }, Script { sent, replies })
+    }
+
+    impl Script {
+        async fn next(&mut self) -> (u16, TMessage) {
+            time::timeout(Duration::from_secs(5), self.sent.recv()).await.expect("nothing sent").unwrap()
+        }
+
+        /// Waits for everything to settle, checking nothing goes out meanwhile.
+        async fn nothing_sent(&mut self) {
+            if let Ok(req) = time::timeout(Duration::from_millis(100), self.sent.recv()).await {
+                panic!("unexpected request {req:?}");
+            }
+        }
+
+        fn reply(&self, tag: u16, resp: RMessage) {
+            self.replies.send(resp.serialize(tag).unwrap()).unwrap();
+        }
 
-        let _handle = tokio::spawn(async move {
-            let mut resp = BytesMut::zeroed(MAX_MESSAGE_SIZE as usize);
+        async fn version(&mut self) {
+            let (tag, TMessage::Tversion(Tversion { msize, version })) = self.next().await else { panic!("expected a version") };
+            self.reply(tag, RMessage::Rversion(Rversion { msize, version }));
+        }
+
+        /// Answers an attach, returning its fid, uname and aname.
+        async fn attach(&mut self) -> (u32, ByteString, ByteString) {
+            let (tag, TMessage::Tattach(Tattach { fid, uname, aname, .. })) = self.next().await else { panic!("expected an attach") };
+            self.reply(tag, RMessage::Rattach(Rattach { qid: DIR }));
+            (fid, uname, aname)
+        }
+
+        /// Answers the walk to a file and the open after it.
+        async fn open(&mut self) {
+            let (tag, TMessage::Twalk(_)) = self.next().await else { panic!("expected a walk") };
+            self.reply(tag, RMessage::Rwalk(Rwalk { wqid: vec![FILE] }));
+            let (tag, TMessage::Topen(Topen { .. })) = self.next().await else { panic!("expected an open") };
+            self.reply(tag, RMessage::Ropen(Ropen { qid: FILE, iounit: 0 }));
+        }
+
+        /// Takes a Twrite, returning its tag, offset and data.
+        async fn write(&mut self) -> (u16, u64, Bytes) {
+            let (tag, TMessage::Twrite(Twrite { offset, data, .. })) = self.next().await else { panic!("expected a write") };
+            (tag, offset, data)
+        }
+
+        /// Takes a Tread, returning its tag, offset and count.
+        async fn read(&mut self) -> (u16, u64, u32) {
+            let (tag, TMessage::Tread(Tread { offset, count, .. })) = self.next().await else { panic!("expected a read") };
+            (tag, offset, count)
+        }
+
+        /// Answers a Tread with what's there in a file of `len` bytes.
+        fn reply_read(&self, tag: u16, offset: u64, count: u32, len: u64) {
+            let end = len.min(offset + u64::from(count)).max(offset);
+            let data = pattern(offset, (end - offset) as usize).into();
+            self.reply(tag, RMessage::Rread(Rread { data }));
+        }
+
+        /// Takes a Tread, checking it's at `offset`, and answers it from a file of `len`
+        /// bytes.
+        async fn read_at(&mut self, offset: u64, len: u64) {
+            let (tag, at, count) = self.read().await;
+            assert_eq!(at, offset);
+            self.reply_read(tag, at, count, len);
+        }
+
+        /// Answers every Tread from a file of `len` bytes, and every Tflush, from here on.
+        async fn serve(&mut self, len: u64) {
             loop {
-                let n = inner2.transport.recv(&mut resp).await?;
-                let mut resp = mem::replace(&mut resp, BytesMut::zeroed(MAX_MESSAGE_SIZE as usize));
-                resp.truncate(n);
-                if let Ok((tag, resp)) = deserialize_r(resp.freeze()) {
-                    trace!("received reply with tag {tag}, {resp:?}");
-
-                    if let Some(reply_to) = inner2.inflight.lock().remove(&tag) {
-                        let _ = reply_to.send(resp);
-                    }
+                match self.next().await {
+                    (tag, TMessage::Tread(Tread { offset, count, .. })) => self.reply_read(tag, offset, count, len),
+                    (tag, TMessage::Tflush(_)) => self.reply(tag, RMessage::Rflush(Rflush)),
+                    req => panic!("unexpected request {req:?}")
                 }
             }
+        }
+
+        /// Answers `n` Tflushes, returning the tags they flushed in order.
+        async fn flushes(&mut self, n: usize) -> Vec<u16> {
+            let mut oldtags = Vec::new();
+            for _ in 0..n {
+                let (tag, TMessage::Tflush(Tflush { oldtag })) = self.next().await else { panic!("expected a flush") };
+                self.reply(tag, RMessage::Rflush(Rflush));
+                oldtags.push(oldtag);
+            }
+            oldtags.sort_unstable();
+            oldtags
+        }
+
+        /// Answers a walk from the root with one qid per name, returning its tag and newfid.
+        async fn walk(&mut self) -> (u16, u32) {
+            let (tag, TMessage::Twalk(Twalk { newfid, wname, .. })) = self.next().await else { panic!("expected a walk") };
+            self.reply(tag, RMessage::Rwalk(Rwalk { wqid: vec![DIR; wname.len()] }));
+            (tag, newfid)
+        }
+    }
+
+    /// A walk given up on once it's out, with its Tflush sent.
+    struct Abandoned {
+        fsys: Filesystem,
+        root: Directory,
+        script: Script,
+        walk_tag: u16,
+        newfid: u32,
+        flush_tag: u16
+    }
 
-            #[allow(unreachable_code)]
-            Ok::<_, anyhow::Error>(())
+    async fn abandon_walk() -> Abandoned {
+        let (transport, mut script) = scripted();
+        let (fsys, ()) = tokio::join!(Filesystem::new(transport), script.version());
+        let fsys = fsys.unwrap();
+        let (root, _) = tokio::join!(fsys.attach("me", ""), script.attach());
+        let root = root.unwrap();
+
+        let (walk_tag, newfid) = tokio::select! {
+            _ = root.open_dir_at("a") => panic!("the walk went through unanswered"),
+            (tag, req) = script.next() => {
+                let TMessage::Twalk(Twalk { newfid, .. }) = req else { panic!("expected a walk") };
+                (tag, newfid)
+            }
+        };
+        let (flush_tag, TMessage::Tflush(Tflush { oldtag })) = script.next().await else { panic!("expected a flush") };
+        assert_eq!(oldtag, walk_tag);
+        // The directory that was being walked to is dropped, but its fid can't be clunked
+        // before we know whether the walk happened
+        script.nothing_sent().await;
+
+        Abandoned { fsys, root, script, walk_tag, newfid, flush_tag }
+    }
+
+    /// Checks the tag and fid are free again, by walking with them.
+    async fn reused(root: &Directory, script: &mut Script, walk_tag: u16, newfid: u32) {
+        let (dir, (tag, fid)) = tokio::join!(root.open_dir_at("b"), script.walk());
+        dir.unwrap();
+        assert_eq!((tag, fid), (walk_tag, newfid));
+    }
+
+    #[tokio::test]
+    async fn clunks_what_a_flushed_walk_made() {
+        let Abandoned { fsys, root, mut script, walk_tag, newfid, flush_tag } = abandon_walk().await;
+
+        script.reply(walk_tag, RMessage::Rwalk(Rwalk { wqid: vec![DIR] }));
+        script.nothing_sent().await;
+        script.reply(flush_tag, RMessage::Rflush(Rflush));
+
+        let (tag, TMessage::Tclunk(Tclunk { fid })) = script.next().await else { panic!("expected a clunk") };
+        assert_eq!(fid, newfid);
+        script.reply(tag, RMessage::Rclunk(Rclunk));
+        script.nothing_sent().await;
+        assert!(fsys.fsys.inflight.lock().is_empty());
+
+        reused(&root, &mut script, walk_tag, newfid).await;
+    }
+
+    #[tokio::test]
+    async fn frees_what_a_flushed_walk_never_made() {
+        let Abandoned { fsys, root, mut script, walk_tag, newfid, flush_tag } = abandon_walk().await;
+
+        script.reply(flush_tag, RMessage::Rflush(Rflush));
+        // Nothing to clunk
+        script.nothing_sent().await;
+        assert!(fsys.fsys.inflight.lock().is_empty());
+
+        reused(&root, &mut script, walk_tag, newfid).await;
+    }
+
+    #[tokio::test]
+    async fn restores_fids_whose_root_is_gone() {
+        let (first, mut script) = scripted();
+        let (second, mut again) = scripted();
+        let transports = Mutex::new(vec![second, first]);
+        let connect = move || {
+            let transport = transports.lock().pop();
+            async move { transport.ok_or_else(|| io::Error::other("no more connections")) }
+        };
+
+        let (fsys, ()) = tokio::join!(Filesystem::resilient(connect), script.version());
+        let fsys = fsys.unwrap();
+        let (root, (root_fid, ..)) = tokio::join!(fsys.attach("me", "there"), script.attach());
+        let root = root.unwrap();
+        let (dir, (_, fid)) = tokio::join!(root.open_dir_at("a/b"), script.walk());
+        let dir = dir.unwrap();
+
+        // Its number goes back to be handed out again
+        drop(root);
+        let (tag, TMessage::Tclunk(Tclunk { fid: clunked })) = script.next().await else { panic!("expected a clunk") };
+        assert_eq!(clunked, root_fid);
+        script.reply(tag, RMessage::Rclunk(Rclunk));
+        script.nothing_sent().await;
+
+        drop(script);
+        again.version().await;
+        assert_eq!(again.attach().await, (fid, "me".into(), "there".into()));
+        let (tag, TMessage::Twalk(walk)) = again.next().await else { panic!("expected a walk") };
+        assert_eq!((walk.fid, walk.newfid, walk.wname.as_slice()), (fid, fid, &["a".into(), "b".into()][..]));
+        again.reply(tag, RMessage::Rwalk(Rwalk { wqid: vec![DIR; 2] }));
+
+        time::timeout(Duration::from_secs(5), async {
+            while fsys.health() != Health::Connected {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("never reconnected");
+        again.nothing_sent().await;
+        drop(dir);
+    }
+
+    /// Connects, attaches and opens a file, holding onto the root so it isn't clunked.
+    async fn open_file() -> (Directory, File, Script) {
+        let (transport, mut script) = scripted();
+        let (fsys, ()) = tokio::join!(Filesystem::new(transport), script.version());
+        let fsys = fsys.unwrap();
+        let (root, _) = tokio::join!(fsys.attach("me", ""), script.attach());
+        let root = root.unwrap();
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        let (file, ()) = tokio::join!(root.open_with("f", &options), script.open());
+        (root, file.unwrap(), script)
+    }
+
+    #[tokio::test]
+    async fn sends_writes_before_flushing() {
+        let (_root, file, mut script) = open_file().await;
+        let size = file.fsys.write_size();
+        let data = pattern(0, 3 * size);
+
+        let mut writer = FileWriter::with_write_behind(&file, 8);
+        writer.write_all(&data).await.unwrap();
+        for i in 0..3 {
+            let (tag, offset, sent) = script.write().await;
+            assert_eq!(offset, (i * size) as u64);
+            assert_eq!(sent, data[i * size..][..size]);
+            script.reply(tag, RMessage::Rwrite(Rwrite { count: size as u32 }));
+        }
+
+        writer.flush().await.unwrap();
+        script.nothing_sent().await;
+    }
+
+    #[tokio::test]
+    async fn resends_the_rest_of_short_writes() {
+        let (_root, file, mut script) = open_file().await;
+        let data = pattern(0, file.fsys.write_size());
+
+        let mut writer = FileWriter::new(&file);
+        writer.write_all(&data).await.unwrap();
+        let (res, written) = tokio::join!(writer.flush(), async {
+            let (tag, offset, first) = script.write().await;
+            assert_eq!(offset, 0);
+            script.reply(tag, RMessage::Rwrite(Rwrite { count: 100 }));
+
+            let (tag, offset, rest) = script.write().await;
+            assert_eq!(offset, 100);
+            script.reply(tag, RMessage::Rwrite(Rwrite { count: rest.len() as u32 }));
+            [&first[..100], &rest[..]].concat()
         });
+        res.unwrap();
+        assert_eq!(written, data);
+        script.nothing_sent().await;
+    }
 
-        tokio::spawn(async move {
-            let res = _handle.await.unwrap();
-            if let Err(e) = res {
-                println!("fatal error: {e:?}")
+    #[tokio::test]
+    async fn returns_the_first_write_error_from_then_on() {
+        let (_root, file, mut script) = open_file().await;
+        let size = file.fsys.write_size();
+
+        let mut writer = FileWriter::with_write_behind(&file, 8);
+        writer.write_all(&pattern(0, 3 * size)).await.unwrap();
+        let mut tags = Vec::new();
+        for _ in 0..3 {
+            tags.push(script.write().await.0);
+        }
+        script.reply(tags[0], RMessage::Rerror(Rerror { ename: "No space left on device".into() }));
+        script.nothing_sent().await;
+
+        let e = writer.write(b"more").await.unwrap_err();
+        assert_eq!(e.to_string(), "No space left on device");
+        // The writes after it are no use anymore
+        assert_eq!(script.flushes(2).await, tags[1..]);
+
+        assert_eq!(writer.flush().await.unwrap_err().to_string(), "No space left on device");
+        assert_eq!(writer.shutdown().await.unwrap_err().to_string(), "No space left on device");
+        script.nothing_sent().await;
+    }
+
+    #[tokio::test]
+    async fn reads_ahead_in_order() {
+        let (_root, file, mut script) = open_file().await;
+        let size = u64::from(file.fsys.read_size());
+        let len = 13 * size + 100;
+
+        let mut reader = FileReader::with_read_ahead(&file, 8);
+        let mut out = Vec::new();
+        tokio::select! {
+            res = reader.read_to_end(&mut out) => { res.unwrap(); },
+            () = async {
+                // One at first, then twice as many after every full reply
+                script.read_at(0, len).await;
+                let (second, third) = (script.read().await, script.read().await);
+                assert_eq!((second.1, third.1), (size, 2 * size));
+                script.nothing_sent().await;
+                script.reply_read(second.0, second.1, second.2, len);
+
+                let mut reads = Vec::new();
+                for i in 3..6 {
+                    let read = script.read().await;
+                    assert_eq!(read.1, i * size);
+                    reads.push(read);
+                }
+                script.nothing_sent().await;
+
+                // Out of order, they still come out in order
+                reads.push(third);
+                for &(tag, offset, count) in reads.iter().rev() {
+                    script.reply_read(tag, offset, count, len);
+                }
+                let mut reads = Vec::new();
+                for i in 6..14 {
+                    let read = script.read().await;
+                    assert_eq!(read.1, i * size);
+                    reads.push(read);
+                }
+                script.nothing_sent().await;
+                for &(tag, offset, count) in reads.iter().rev() {
+                    script.reply_read(tag, offset, count, len);
+                }
+                script.serve(len).await;
+            } => unreachable!()
+        }
+        assert_eq!(out, pattern(0, len as usize));
+    }
+
+    #[tokio::test]
+    async fn short_reads_flush_the_reads_after_them() {
+        let (_root, file, mut script) = open_file().await;
+        let size = u64::from(file.fsys.read_size());
+        let len = 2 * size + 10;
+
+        let mut reader = FileReader::with_read_ahead(&file, 4);
+        let mut out = Vec::new();
+        let mut after = Vec::new();
+        let mut flushed = Vec::new();
+        tokio::select! {
+            res = reader.read_to_end(&mut out) => { res.unwrap(); },
+            () = async {
+                script.read_at(0, 10 * size).await;
+                let (second, third) = (script.read().await, script.read().await);
+                script.reply_read(second.0, second.1, second.2, 10 * size);
+                for _ in 0..3 {
+                    after.push(script.read().await.0);
+                }
+
+                // The file ends at the third read, so the ones after it were for nothing.
+                // The Tflushes are sent from elsewhere, so the next Tread may beat them.
+                script.reply_read(third.0, third.1, third.2, len);
+                loop {
+                    match script.next().await {
+                        (tag, TMessage::Tflush(Tflush { oldtag })) => {
+                            script.reply(tag, RMessage::Rflush(Rflush));
+                            flushed.push(oldtag);
+                        },
+                        (tag, TMessage::Tread(Tread { offset, count, .. })) => {
+                            assert_eq!(offset, len);
+                            script.reply_read(tag, offset, count, len);
+                        },
+                        req => panic!("unexpected request {req:?}")
+                    }
+                }
+            } => unreachable!()
+        }
+        assert_eq!(out, pattern(0, len as usize));
+
+        flushed.extend(script.flushes(3 - flushed.len()).await);
+        flushed.sort_unstable();
+        assert_eq!(flushed, after);
+        script.nothing_sent().await;
+    }
+
+    #[tokio::test]
+    async fn seeking_flushes_reads_and_starts_over() {
+        let (_root, file, mut script) = open_file().await;
+        let size = file.fsys.read_size() as usize;
+        let len = 10 * size as u64;
+
+        let mut reader = FileReader::with_read_ahead(&file, 4);
+        let mut buf = vec![0; size];
+        let (n, ()) = tokio::join!(reader.read(&mut buf), script.read_at(0, len));
+        assert_eq!(n.unwrap(), size);
+        let (n, third) = tokio::join!(reader.read(&mut buf), async {
+            let (second, third) = (script.read().await, script.read().await);
+            script.reply_read(second.0, second.1, second.2, len);
+            third
+        });
+        assert_eq!(n.unwrap(), size);
+        let (n, out) = tokio::join!(reader.read(&mut buf), async {
+            let mut out = Vec::new();
+            for _ in 0..3 {
+                out.push(script.read().await.0);
             }
+            script.reply_read(third.0, third.1, third.2, len);
+            out
         });
+        assert_eq!(n.unwrap(), size);
+        assert_eq!(buf, pattern(2 * size as u64, size));
 
-        Ok(Self {
-            fsys: inner
-        })
+        let (offset, flushed) = tokio::join!(reader.seek(io::SeekFrom::Start(100)), script.flushes(3));
+        assert_eq!(offset.unwrap(), 100);
+        assert_eq!(flushed, out);
+        let (n, ()) = tokio::join!(reader.read(&mut buf), script.read_at(100, len));
+        assert_eq!(n.unwrap(), size);
+        assert_eq!(buf, pattern(100, size));
+        // With the window back to one
+        script.nothing_sent().await;
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn keeps_what_the_caller_had_no_room_for() {
+        let (_root, file, mut script) = open_file().await;
+        let size = file.fsys.read_size() as usize;
+
+        let mut reader = FileReader::new(&file);
+        let mut buf = [0; 10];
+        let (n, ()) = tokio::join!(reader.read(&mut buf), script.read_at(0, 10 * size as u64));
+        assert_eq!(n.unwrap(), 10);
+
+        let mut rest = vec![0; size - 10];
+        reader.read_exact(&mut rest).await.unwrap();
+        assert_eq!([&buf[..], &rest].concat(), pattern(0, size));
+        script.nothing_sent().await;
+    }
+}
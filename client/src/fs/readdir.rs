@@ -1,11 +1,13 @@
 use std::{collections::VecDeque, io, mem};
 
-use npwire::{yank_stat, Stat, QTDIR};
+use npwire::{yank_stat, Stat, OREAD, QTDIR};
 
 use super::{Directory, File};
 
 pub struct ReadDir {
     offset: u64,
+    /// Reading on from `offset` only works on the connection it was opened on
+    generation: u64,
     file: File,
     buffer: VecDeque<npwire::Stat>
 }
@@ -17,13 +19,14 @@ impl Directory {
         mem::forget(self);
 
         let file = File { fsys, fid };
-        let qid = file.fsys.open(&file.fid).await?;
+        let qid = file.fsys.open(&file.fid, OREAD).await?;
 
         if qid.type_ & QTDIR != QTDIR {
             return Err(io::ErrorKind::NotADirectory.into());
         }
 
-        Ok(ReadDir { offset: 0, file, buffer: VecDeque::new() })
+        let generation = file.fsys.generation();
+        Ok(ReadDir { offset: 0, generation, file, buffer: VecDeque::new() })
     }
 }
 
@@ -37,6 +40,12 @@ impl ReadDir {
             return Ok(Some(stat));
         }
         
+        // A reconnect reopens the directory, which can then only be read from the start
+        if self.offset != 0 && self.generation != self.file.fsys.generation() {
+            return Err(io::Error::new(io::ErrorKind::ConnectionReset, "reconnected partway through reading the directory, rewind to start over"));
+        }
+        self.generation = self.file.fsys.generation();
+
         let mut data = self.file.read_at(u32::MAX, self.offset).await?;
         self.offset += u64::try_from(data.len()).map_err(io::Error::other)?;
         
@@ -50,6 +59,7 @@ impl ReadDir {
 
     pub fn rewind(&mut self) {
         self.offset = 0;
+        self.generation = self.file.fsys.generation();
         self.buffer.clear();
     }
 }
\ No newline at end of file
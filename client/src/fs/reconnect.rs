@@ -0,0 +1,299 @@
+//! Opt-in reconnecting (see [`Filesystem::resilient`](super::Filesystem::resilient)).
+//! Fids are ours to number, so on a new connection every fid is attached again under the
+//! number it had and walked back to where it was, and the handles holding them never know.
+
+use std::{collections::HashMap, future::Future, io, sync::{atomic::Ordering, Arc}, time::Duration};
+
+use async_trait::async_trait;
+use bytestring::ByteString;
+use futures::future::BoxFuture;
+use npwire::{RMessage, TMessage, Tattach, Tclunk, Topen, Twalk, OTRUNC};
+use parking_lot::{Mutex, RwLock};
+use tokio::time::{self, Instant};
+use tracing::{debug, warn};
+
+use super::{dir::MAXWELEM, version, DynFilesystemInner, Health, Inflight, Transport};
+
+/// How long to keep trying to reconnect before giving up for good
+pub const RECONNECT_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(30);
+
+type DynTransport = Arc<dyn Transport + Send + Sync>;
+type Connect = Box<dyn Fn() -> BoxFuture<'static, io::Result<DynTransport>> + Send + Sync>;
+
+/// Whether a request can go out again on a new connection without doing anything
+/// twice. Walks and opens can, since fids from the old connection are gone anyway.
+pub(super) fn replayable(message: &TMessage) -> bool {
+    matches!(message,
+        TMessage::Tattach(_) | TMessage::Twalk(_) | TMessage::Topen(_) | TMessage::Tread(_)
+        | TMessage::Treads(_) | TMessage::Tstat(_) | TMessage::Tclunk(_))
+}
+
+/// The fid a request uses, if it uses one that has to exist already.
+fn fid_of(message: &TMessage) -> Option<u32> {
+    match message {
+        TMessage::Twalk(m) => Some(m.fid),
+        TMessage::Topen(m) => Some(m.fid),
+        TMessage::Tcreate(m) => Some(m.fid),
+        TMessage::Tread(m) => Some(m.fid),
+        TMessage::Treads(m) => Some(m.fid),
+        TMessage::Twrite(m) => Some(m.fid),
+        TMessage::Tclunk(m) => Some(m.fid),
+        TMessage::Tremove(m) => Some(m.fid),
+        TMessage::Tstat(m) => Some(m.fid),
+        TMessage::Twstat(m) => Some(m.fid),
+        TMessage::Tversion(_) | TMessage::Tauth(_) | TMessage::Tflush(_) | TMessage::Tattach(_) => None
+    }
+}
+
+/// Whatever it takes to get a fid back.
+#[derive(Debug, Clone)]
+struct FidState {
+    /// The uname and aname of the attach it was walked from
+    attach: (ByteString, ByteString),
+    /// From the root of that attach
+    path: Vec<ByteString>,
+    /// Without OTRUNC, which shouldn't happen twice
+    mode: Option<u8>,
+    /// Why it couldn't be put back
+    lost: Option<String>
+}
+
+/// The transport in use, which is swapped for a new one on reconnecting.
+pub(super) struct Swappable(Arc<RwLock<DynTransport>>);
+
+#[async_trait]
+impl Transport for Swappable {
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let transport = self.0.read().clone();
+        transport.recv(buf).await
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let transport = self.0.read().clone();
+        transport.send(buf).await
+    }
+}
+
+pub(super) struct Reconnect {
+    connect: Connect,
+    current: Arc<RwLock<DynTransport>>,
+    fids: Mutex<HashMap<u32, FidState>>
+}
+
+impl Reconnect {
+    /// Connects for the first time, returning the transport to use from then on and the
+    /// message size.
+    pub(super) async fn new<F, Fut, T>(connect: F) -> io::Result<(Self, Swappable, usize)>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<T>> + Send + 'static,
+        T: Transport + Send + Sync + 'static
+    {
+        let connect: Connect = Box::new(move || {
+            let fut = connect();
+            Box::pin(async move { Ok(Arc::new(fut.await?) as DynTransport) })
+        });
+        let transport = connect().await?;
+        let maxlen = version(&*transport).await?;
+        let current = Arc::new(RwLock::new(transport));
+        let reconnect = Self { connect, current: current.clone(), fids: Mutex::default() };
+        Ok((reconnect, Swappable(current), maxlen))
+    }
+
+    /// Connects again, backing off between tries, and swaps the new transport in.
+    /// Returns the new message size.
+    pub(super) async fn connect(&self) -> io::Result<usize> {
+        let give_up = Instant::now() + RECONNECT_TIMEOUT;
+        let mut retry = FIRST_RETRY;
+        loop {
+            let res = async {
+                let transport = (self.connect)().await?;
+                let maxlen = version(&*transport).await?;
+                Ok::<_, io::Error>((transport, maxlen))
+            };
+            match time::timeout_at(give_up, res).await {
+                Ok(Ok((transport, maxlen))) => {
+                    *self.current.write() = transport;
+                    return Ok(maxlen);
+                },
+                Ok(Err(e)) if Instant::now() + retry < give_up => {
+                    debug!("couldn't reconnect, trying again in {retry:?}: {e}");
+                    time::sleep(retry).await;
+                    retry = (retry * 2).min(MAX_RETRY);
+                },
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Err(io::ErrorKind::TimedOut.into())
+            }
+        }
+    }
+
+    pub(super) fn attached(&self, fid: u32, uname: &str, aname: &str) {
+        self.fids.lock().insert(fid, FidState {
+            attach: (uname.into(), aname.into()),
+            path: Vec::new(),
+            mode: None,
+            lost: None
+        });
+    }
+
+    pub(super) fn walked(&self, fid: u32, newfid: u32, wname: &[ByteString]) {
+        let mut fids = self.fids.lock();
+        let Some(from) = fids.get(&fid) else { return };
+        let mut path = from.path.clone();
+        path.extend_from_slice(wname);
+        let state = FidState { attach: from.attach.clone(), path, mode: None, lost: None };
+        fids.insert(newfid, state);
+    }
+
+    pub(super) fn opened(&self, fid: u32, mode: u8) {
+        if let Some(state) = self.fids.lock().get_mut(&fid) {
+            state.mode = Some(mode & !OTRUNC);
+        }
+    }
+
+    pub(super) fn created(&self, fid: u32, name: &str, mode: u8) {
+        if let Some(state) = self.fids.lock().get_mut(&fid) {
+            state.path.push(name.into());
+            state.mode = Some(mode & !OTRUNC);
+        }
+    }
+
+    /// Fids walked from a renamed directory still have the old name in their paths, and
+    /// are lost if the connection drops.
+    pub(super) fn renamed(&self, fid: u32, name: &str) {
+        if let Some(state) = self.fids.lock().get_mut(&fid) && let Some(last) = state.path.last_mut() {
+            *last = name.into();
+        }
+    }
+
+    pub(super) fn forget(&self, fid: u32) {
+        self.fids.lock().remove(&fid);
+    }
+
+    /// Fails requests on fids that didn't survive a reconnect, with why.
+    pub(super) fn check(&self, message: &TMessage) -> io::Result<()> {
+        let Some(fid) = fid_of(message) else { return Ok(()) };
+        match self.fids.lock().get(&fid).and_then(|state| state.lost.clone()) {
+            Some(lost) => Err(io::Error::new(io::ErrorKind::NotConnected, format!("lost in a reconnect: {lost}"))),
+            None => Ok(())
+        }
+    }
+
+    pub(super) fn is_lost(&self, fid: u32) -> bool {
+        self.fids.lock().get(&fid).is_some_and(|state| state.lost.is_some())
+    }
+
+    fn lose(&self, fid: u32, why: String) {
+        warn!("couldn't restore fid {fid} after reconnecting: {why}");
+        if let Some(state) = self.fids.lock().get_mut(&fid) {
+            state.lost = Some(why);
+        }
+    }
+}
+
+/// What a restoring request came back with: the fid's lost if the server said no, but
+/// a failed connection means starting over.
+fn refused(resp: io::Result<RMessage>) -> io::Result<Option<String>> {
+    Ok(match resp? {
+        RMessage::Rerror(e) => Some(e.ename.to_string()),
+        _ => None
+    })
+}
+
+impl DynFilesystemInner {
+    /// Holds things up while reconnecting. Requests that can be sent again keep their
+    /// tags, and their replies go where they would have. The rest fail.
+    pub(super) fn suspend(&self) -> Vec<u16> {
+        let mut inflight = self.inflight.lock();
+        self.health.send_replace(Health::Reconnecting);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+
+        let mut replay = Vec::new();
+        for (tag, entry) in std::mem::take(&mut *inflight) {
+            match entry {
+                Inflight::Waiting { replay: Some(_), .. } => {
+                    replay.push(tag);
+                    inflight.insert(tag, entry);
+                },
+                Inflight::Waiting { reply_to, .. } => {
+                    let _ = reply_to.send(Err(io::Error::new(io::ErrorKind::ConnectionReset,
+                        "the connection dropped before the server answered, so this may or may not have happened")));
+                },
+                // The server that had them is gone
+                Inflight::Flushing { .. } => ()
+            }
+        }
+        replay
+    }
+
+    /// Puts every fid back on the new connection, then sends `replay` again and lets
+    /// everything else through. Gives up quietly if the connection drops meanwhile, since
+    /// the next one starts over.
+    pub(super) async fn restore(&self, generation: u64, replay: Vec<u16>) {
+        let Some(reconnect) = &self.reconnect else { return };
+        if let Err(e) = self.restore_fids(reconnect).await {
+            debug!("gave up restoring fids: {e}");
+            return;
+        }
+
+        for tag in replay {
+            let Some(data) = (match self.inflight.lock().get(&tag) {
+                Some(Inflight::Waiting { replay, .. }) => replay.clone(),
+                _ => None
+            }) else { continue };
+            if let Err(e) = self.transport.send(&data).await {
+                debug!("gave up replaying requests: {e}");
+                return;
+            }
+        }
+
+        let _inflight = self.inflight.lock();
+        if self.generation() == generation && *self.health.borrow() == Health::Reconnecting {
+            self.health.send_replace(Health::Connected);
+        }
+    }
+
+    /// Attaches every fid on its own, so none depends on another still being around, then
+    /// walks it back to its path and opens it again.
+    async fn restore_fids(&self, reconnect: &Reconnect) -> io::Result<()> {
+        let fids = reconnect.fids.lock().iter()
+            .filter(|(_, state)| state.lost.is_none())
+            .map(|(&fid, state)| (fid, state.clone()))
+            .collect::<Vec<_>>();
+
+        for (fid, state) in fids {
+            let (uname, aname) = state.attach.clone();
+            let resp = self.request(Tattach { fid, afid: !0, uname, aname }.into(), true).await;
+            if let Some(why) = refused(resp)? {
+                reconnect.lose(fid, why);
+                continue;
+            }
+
+            let mut why = self.rewalk(fid, &state).await?;
+            if why.is_none() && let Some(mode) = state.mode {
+                why = refused(self.request(Topen { fid, mode }.into(), true).await)?;
+            }
+            if let Some(why) = why {
+                // It's attached all the same, and a lost fid is never clunked otherwise
+                self.request(Tclunk { fid }.into(), true).await?;
+                reconnect.lose(fid, why);
+            }
+        }
+        Ok(())
+    }
+
+    /// Walks the freshly attached `fid` to its path again, a few names at a time.
+    async fn rewalk(&self, fid: u32, state: &FidState) -> io::Result<Option<String>> {
+        for wname in state.path.chunks(MAXWELEM) {
+            let resp = self.request(Twalk { fid, newfid: fid, wname: wname.to_vec() }.into(), true).await?;
+            match resp {
+                RMessage::Rerror(e) => return Ok(Some(e.ename.to_string())),
+                RMessage::Rwalk(walk) if walk.wqid.len() == wname.len() => (),
+                _ => return Ok(Some("not found".to_owned()))
+            }
+        }
+        Ok(None)
+    }
+}
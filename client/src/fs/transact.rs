@@ -1,45 +1,169 @@
-use std::io;
+use std::{io, time::Duration};
 
 use bytestring::ByteString;
-use npwire::{RMessage, Rclunk, Rerror, Ropen, Rstat, Rwalk, TMessage, Tclunk, Topen, Tstat, Twalk, Twrite, TWRITE_OVERHEAD};
-use tokio::sync::oneshot;
-use tracing::trace;
+use npwire::{RMessage, Rclunk, Rcreate, Rerror, Rflush, Ropen, Rremove, Rstat, Rwalk, Rwstat, TMessage, Tclunk, Tcreate, Tflush, Topen, Tremove, Tstat, Twalk, Twrite, Twstat};
+use tokio::{sync::oneshot::{self, error::TryRecvError}, time};
+use tracing::{trace, warn};
 use util::fidpool::FidHandle;
 
-use super::{Transport, FilesystemInner};
+use super::{reconnect::replayable, FilesystemInner, Health, Inflight, NewFid, StatChanges, Transport};
+
+const FIRST_FLUSH_RETRY: Duration = Duration::from_millis(100);
+const MAX_FLUSH_RETRY: Duration = Duration::from_secs(5);
+
+/// Holds a tag while its request is out, and flushes it if dropped before the reply.
+struct PendingTag<'a, T: ?Sized> {
+    fsys: &'a FilesystemInner<T>,
+    tag: u16,
+    reply: oneshot::Receiver<io::Result<RMessage>>
+}
+
+impl<T: ?Sized> PendingTag<'_, T> {
+    /// Frees the tag right away, for a request that never went out.
+    fn release(self) {
+        self.fsys.inflight.lock().remove(&self.tag);
+    }
+}
+
+impl<T: ?Sized> Drop for PendingTag<'_, T> {
+    fn drop(&mut self) {
+        let mut inflight = self.fsys.inflight.lock();
+        // Replies are sent under the lock, so if ours hasn't come, the tag is still ours.
+        // Otherwise it's free already, and maybe someone else's by now.
+        if !matches!(self.reply.try_recv(), Err(TryRecvError::Empty)) {
+            return;
+        }
+        let Some(Inflight::Waiting { slot, newfid, .. }) = inflight.remove(&self.tag) else { return };
+        inflight.insert(self.tag, Inflight::Flushing { slot, newfid, made: false, held: None });
+        if self.fsys.flushes.send(self.tag).is_err() {
+            // Nobody's left to flush it, and nobody's left to reuse it either
+            inflight.remove(&self.tag);
+        }
+    }
+}
 
 impl<T: Transport + ?Sized> FilesystemInner<T> {
+    /// Sends a request and waits for the reply. Dropping the future flushes the request,
+    /// and its tag stays reserved until the server acknowledges that.
     pub(super) async fn transact(&self, message: impl Into<TMessage>) -> io::Result<RMessage> {
-        let mut message = message.into();
-        
-        let (tag, rcv) = {
-            let mut inflight = self.inflight.lock();
-            let mut iter = inflight.keys();
-            let mut tag = 0;
-            while iter.next().copied() == Some(tag) {
-                // todo: wait if the queue is full
-                tag = tag.checked_add(1).and_then(|t| if t == !0 { None } else { Some(t) }).unwrap();
-            }
+        self.request(message.into(), false).await
+    }
+
+    /// Like [`transact`](Self::transact). `restoring` requests put fids back after
+    /// reconnecting, so they go ahead of everything else, and are never sent again.
+    pub(super) async fn request(&self, mut message: TMessage, restoring: bool) -> io::Result<RMessage> {
+        if let Some(reconnect) = &self.reconnect && !restoring {
+            reconnect.check(&message)?;
+        }
 
-            let (reply_to, rcv) = oneshot::channel();
-            let unique = inflight.insert(tag, reply_to).is_none();
-            assert!(unique);
-            (tag, rcv)
+        let mut slot = if matches!(message, TMessage::Tflush(_)) || restoring {
+            None
+        } else {
+            // Only fails once the connection's closed
+            Some(self.slots.clone().acquire_owned().await.map_err(|_| self.aborted())?)
         };
 
         // Bound writes by the max message size
         if let TMessage::Twrite(Twrite { ref mut data, .. }) = message {
-            data.truncate(self.maxlen - TWRITE_OVERHEAD);
+            data.truncate(self.write_size());
         }
 
-        let data = message
-            .serialize(tag)
-            .unwrap_or_else(|e| Rerror::from(e).serialize(tag).unwrap());
+        let replayable = self.reconnect.is_some() && !restoring && replayable(&message);
+        let newfid = NewFid::of(&message);
+        let mut health = self.health.subscribe();
+        let (tag, data, reply) = loop {
+            {
+                let mut inflight = self.inflight.lock();
+                match &*health.borrow_and_update() {
+                    Health::Closed(_) => return Err(self.aborted()),
+                    Health::Reconnecting if !restoring => (),
+                    Health::Connected | Health::Reconnecting => {
+                        // A Tflush only means something on the connection its request went
+                        // out on, and reconnecting forgets every request being flushed
+                        if let TMessage::Tflush(Tflush { oldtag }) = message && !matches!(inflight.get(&oldtag), Some(Inflight::Flushing { .. })) {
+                            return Err(io::Error::new(io::ErrorKind::NotFound, "nothing to flush"));
+                        }
 
-        self.transport.send(&data).await?;
+                        let mut iter = inflight.keys();
+                        let mut tag = 0;
+                        while iter.next().copied() == Some(tag) {
+                            // Can't happen, with at most half the tags given out as slots
+                            tag = tag.checked_add(1).filter(|&t| t != !0).ok_or_else(|| io::Error::other("out of tags"))?;
+                        }
+
+                        let data = message
+                            .serialize(tag)
+                            .unwrap_or_else(|e| Rerror::from(e).serialize(tag).unwrap());
+
+                        let (reply_to, rcv) = oneshot::channel();
+                        let replay = replayable.then(|| data.clone());
+                        let unique = inflight.insert(tag, Inflight::Waiting { reply_to, slot: slot.take(), replay, newfid }).is_none();
+                        assert!(unique);
+                        break (tag, data, rcv);
+                    }
+                }
+            }
+            // Wait for the fids to be back
+            let _ = health.changed().await;
+        };
+        let mut pending = PendingTag { fsys: self, tag, reply };
+
+        if let Err(e) = self.transport.send(&data).await {
+            pending.release();
+            return Err(e);
+        }
         trace!(target: "client::fs", "sent request with tag {tag}, {:?}", message);
 
-        rcv.await.map_err(|_| io::ErrorKind::UnexpectedEof.into())
+        // Dropped unanswered only when the connection's closed
+        (&mut pending.reply).await.map_err(|_| self.aborted())?
+    }
+
+    /// Tells the server to forget about `oldtag`, and frees it once it has, clunking
+    /// the fid it made if its reply came first. Per 9P, a Tflush is only ever answered
+    /// with an Rflush.
+    pub(super) async fn flush(&self, oldtag: u16) {
+        let mut retry = FIRST_FLUSH_RETRY;
+        loop {
+            match self.transact(Tflush { oldtag }).await {
+                Ok(RMessage::Rflush(Rflush)) => trace!(target: "client::fs", "flushed request with tag {oldtag}"),
+                Ok(resp) => warn!(target: "client::fs", "unexpected reply to flushing tag {oldtag}: {resp:?}"),
+                // Without an Rflush, the tag is never safe to reuse, so we keep at it until
+                // there's one or the connection's gone, which takes the tag with it
+                Err(e) => {
+                    if !matches!(self.inflight.lock().get(&oldtag), Some(Inflight::Flushing { .. })) {
+                        return;
+                    }
+                    warn!(target: "client::fs", "couldn't flush tag {oldtag}, trying again in {retry:?}: {e}");
+                    time::sleep(retry).await;
+                    retry = (retry * 2).min(MAX_FLUSH_RETRY);
+                    continue;
+                }
+            }
+            break;
+        }
+
+        let flushed = self.inflight.lock().remove(&oldtag);
+        // Otherwise there's no fid on the server, and the handle can go back
+        if let Some(Inflight::Flushing { made: true, held: Some(fid), .. }) = flushed {
+            let _ = self.clunk(fid).await;
+        }
+    }
+
+    /// Takes `fid` off the caller's hands if a request that makes it is being flushed,
+    /// until that's settled. Otherwise gives it back.
+    fn hold(&self, fid: FidHandle) -> Option<FidHandle> {
+        let mut inflight = self.inflight.lock();
+        let flushing = inflight.values_mut().find_map(|entry| match entry {
+            Inflight::Flushing { newfid: Some(newfid), held, .. } if newfid.fid == fid.fid() && held.is_none() => Some(held),
+            _ => None
+        });
+        match flushing {
+            Some(held) => {
+                *held = Some(fid);
+                None
+            },
+            None => Some(fid)
+        }
     }
 
     pub(super) async fn stat(&self, fid: &FidHandle) -> io::Result<npwire::Stat> {
@@ -56,15 +180,65 @@ impl<T: Transport + ?Sized> FilesystemInner<T> {
         }
     }
 
-    pub(super) async fn open(&self, fid: &FidHandle) -> io::Result<npwire::Qid> {
+    pub(super) async fn wstat(&self, fid: &FidHandle, changes: &StatChanges) -> io::Result<()> {
+        assert!(fid.is_of(&self.fids));
+        changes.validate()?;
+
+        let stat = changes.to_stat();
+        let name = stat.name.clone();
+        let resp = self.transact(Twstat {
+            fid: fid.fid(),
+            stat
+        }).await?;
+
+        match resp {
+            RMessage::Rerror(Rerror { ename }) => Err(io::Error::other(&*ename)),
+            RMessage::Rwstat(Rwstat) => {
+                if let Some(reconnect) = &self.reconnect && !name.is_empty() {
+                    reconnect.renamed(fid.fid(), &name);
+                }
+                Ok(())
+            },
+            _ => Err(io::Error::other("unexpected message type"))
+        }
+    }
+
+    pub(super) async fn open(&self, fid: &FidHandle, mode: u8) -> io::Result<npwire::Qid> {
         let resp = self.transact(Topen {
             fid: fid.fid(),
-            mode: 0
+            mode
+        }).await?;
+
+        match resp {
+            RMessage::Rerror(Rerror { ename }) => Err(io::Error::other(&*ename)),
+            RMessage::Ropen(Ropen { qid, iounit: _ }) => {
+                if let Some(reconnect) = &self.reconnect {
+                    reconnect.opened(fid.fid(), mode);
+                }
+                Ok(qid)
+            },
+            _ => Err(io::Error::other("unexpected message type"))
+        }
+    }
+
+    /// Creates `name` in the directory `fid` is on, which then refers to the new file,
+    /// opened with `mode`.
+    pub(super) async fn create(&self, fid: &FidHandle, name: &str, perm: u32, mode: u8) -> io::Result<npwire::Qid> {
+        let resp = self.transact(Tcreate {
+            fid: fid.fid(),
+            name: name.into(),
+            perm,
+            mode
         }).await?;
 
         match resp {
             RMessage::Rerror(Rerror { ename }) => Err(io::Error::other(&*ename)),
-            RMessage::Ropen(Ropen { qid, iounit: _ }) => Ok(qid),
+            RMessage::Rcreate(Rcreate { qid, iounit: _ }) => {
+                if let Some(reconnect) = &self.reconnect {
+                    reconnect.created(fid.fid(), name, mode);
+                }
+                Ok(qid)
+            },
             _ => Err(io::Error::other("unexpected message type"))
         }
     }
@@ -73,12 +247,18 @@ impl<T: Transport + ?Sized> FilesystemInner<T> {
         let resp = self.transact(Twalk {
             fid: fid.fid(),
             newfid: newfid.fid(),
-            wname
+            wname: wname.clone()
         }).await?;
 
         match resp {
             RMessage::Rerror(Rerror { ename }) => Err(io::Error::other(&*ename)),
-            RMessage::Rwalk(Rwalk { wqid }) => Ok(wqid),
+            RMessage::Rwalk(Rwalk { wqid }) => {
+                // Only a whole walk makes the new fid
+                if let Some(reconnect) = &self.reconnect && wqid.len() == wname.len() {
+                    reconnect.walked(fid.fid(), newfid.fid(), &wname);
+                }
+                Ok(wqid)
+            },
             _ => Err(io::Error::other("unexpected message type"))
         }
     }
@@ -86,6 +266,18 @@ impl<T: Transport + ?Sized> FilesystemInner<T> {
     pub(super) async fn clunk(&self, fid: FidHandle) -> io::Result<()> {
         assert!(fid.is_of(&self.fids));
 
+        // Clunked once the flush is done, if there's anything to clunk by then
+        let Some(fid) = self.hold(fid) else { return Ok(()) };
+
+        if let Some(reconnect) = &self.reconnect {
+            // A fid lost in a reconnect is already gone from the server
+            let lost = reconnect.is_lost(fid.fid());
+            reconnect.forget(fid.fid());
+            if lost {
+                return Ok(());
+            }
+        }
+
         let resp = self.transact(Tclunk {
             fid: fid.fid()
         }).await?;
@@ -96,4 +288,24 @@ impl<T: Transport + ?Sized> FilesystemInner<T> {
             _ => Err(io::Error::other("unexpected message type"))
         }
     }
+
+    /// Removes the file `fid` refers to. The fid is clunked whether or not that works.
+    pub(super) async fn remove(&self, fid: FidHandle) -> io::Result<()> {
+        assert!(fid.is_of(&self.fids));
+
+        let resp = self.transact(Tremove {
+            fid: fid.fid()
+        }).await;
+        // Clunked either way
+        if let Some(reconnect) = &self.reconnect {
+            reconnect.forget(fid.fid());
+        }
+        let resp = resp?;
+
+        match resp {
+            RMessage::Rerror(Rerror { ename }) => Err(io::Error::other(&*ename)),
+            RMessage::Rremove(Rremove) => Ok(()),
+            _ => Err(io::Error::other("unexpected message type"))
+        }
+    }
 }
\ No newline at end of file
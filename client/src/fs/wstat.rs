@@ -0,0 +1,153 @@
+use std::{io, time::{SystemTime, UNIX_EPOCH}};
+
+use npwire::{Qid, Stat, DMDIR};
+
+use super::{dir::{check_name, components}, Directory, File};
+
+/// Changes to make to a file's stat with Twstat. Whatever isn't set is left alone, and
+/// sending no changes at all asks the server to commit the file to stable storage.
+#[derive(Debug, Clone)]
+pub struct StatChanges {
+    stat: Stat
+}
+
+impl Default for StatChanges {
+    fn default() -> Self {
+        // 9P's "don't touch" values
+        Self {
+            stat: Stat {
+                type_: !0,
+                dev: !0,
+                qid: Qid { type_: !0, version: !0, path: !0 },
+                mode: !0,
+                atime: !0,
+                mtime: !0,
+                length: !0,
+                name: "".into(),
+                uid: "".into(),
+                gid: "".into(),
+                muid: "".into()
+            }
+        }
+    }
+}
+
+impl StatChanges {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Renames the file, within the directory it's in.
+    pub fn name(&mut self, name: &str) -> &mut Self {
+        self.stat.name = name.into();
+        self
+    }
+
+    /// Sets the mode: the permissions and the `DM*` bits, whose `DMDIR` has to match
+    /// what the file is.
+    pub fn mode(&mut self, mode: u32) -> &mut Self {
+        self.stat.mode = mode;
+        self
+    }
+
+    /// Sets the modification time, in seconds since the epoch.
+    pub fn mtime(&mut self, mtime: u32) -> &mut Self {
+        self.stat.mtime = mtime;
+        self
+    }
+
+    /// Truncates or extends the file.
+    pub fn length(&mut self, length: u64) -> &mut Self {
+        self.stat.length = length;
+        self
+    }
+
+    pub fn gid(&mut self, gid: &str) -> &mut Self {
+        self.stat.gid = gid.into();
+        self
+    }
+
+    pub(super) fn validate(&self) -> io::Result<()> {
+        if !self.stat.name.is_empty() {
+            check_name(&self.stat.name)?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn to_stat(&self) -> Stat {
+        self.stat.clone()
+    }
+}
+
+fn mtime(time: SystemTime) -> io::Result<u32> {
+    time.duration_since(UNIX_EPOCH).ok()
+        .and_then(|since| u32::try_from(since.as_secs()).ok())
+        // The last second 9P can tell is "don't touch"
+        .filter(|&secs| secs != !0)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "time out of range"))
+}
+
+impl File {
+    pub async fn wstat(&self, changes: &StatChanges) -> io::Result<()> {
+        self.fsys.wstat(&self.fid, changes).await
+    }
+
+    /// Renames the file within its directory.
+    pub async fn rename(&self, name: &str) -> io::Result<()> {
+        self.wstat(StatChanges::new().name(name)).await
+    }
+
+    /// Sets the mode bits, which mustn't include `DMDIR`.
+    pub async fn set_permissions(&self, mode: u32) -> io::Result<()> {
+        self.wstat(StatChanges::new().mode(mode & !DMDIR)).await
+    }
+
+    pub async fn set_len(&self, length: u64) -> io::Result<()> {
+        self.wstat(StatChanges::new().length(length)).await
+    }
+
+    pub async fn set_modified(&self, time: SystemTime) -> io::Result<()> {
+        self.wstat(StatChanges::new().mtime(mtime(time)?)).await
+    }
+
+    /// Asks the server to commit the file to stable storage.
+    pub async fn sync(&self) -> io::Result<()> {
+        self.wstat(&StatChanges::new()).await
+    }
+}
+
+impl Directory {
+    pub async fn wstat(&self, changes: &StatChanges) -> io::Result<()> {
+        self.fsys.wstat(&self.fid, changes).await
+    }
+
+    /// Changes whatever is at `path`, file or directory, without opening it.
+    pub async fn wstat_at(&self, path: impl AsRef<str>, changes: &StatChanges) -> io::Result<()> {
+        if components(path.as_ref()).is_empty() {
+            return self.wstat(changes).await;
+        }
+
+        let (node, _) = self.walk_to(path.as_ref()).await?;
+        node.wstat(changes).await
+    }
+
+    /// Renames the directory within its parent.
+    pub async fn rename(&self, name: &str) -> io::Result<()> {
+        self.wstat(StatChanges::new().name(name)).await
+    }
+
+    /// Sets the permission bits, and any other `DM*` bits besides `DMDIR`.
+    pub async fn set_permissions(&self, mode: u32) -> io::Result<()> {
+        self.wstat(StatChanges::new().mode(mode | DMDIR)).await
+    }
+
+    pub async fn set_modified(&self, time: SystemTime) -> io::Result<()> {
+        self.wstat(StatChanges::new().mtime(mtime(time)?)).await
+    }
+
+    /// Asks the server to commit the directory to stable storage.
+    pub async fn sync(&self) -> io::Result<()> {
+        self.wstat(&StatChanges::new()).await
+    }
+}
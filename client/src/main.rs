@@ -1,14 +1,11 @@
 #![forbid(unsafe_code)]
 
-use std::{io, net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6}, sync::Arc, time::Duration};
+use std::{io, time::Duration};
 
-use anyhow::bail;
 use bytestring::ByteString;
+use anyhow::{anyhow, Context};
 use client::{Directory, FileReader, Filesystem};
-use mediator_proto::{mediator_client::MediatorClient, RendezvousRequest};
 use tokio::{io::AsyncReadExt as _, time};
-use transport::SecureTransport;
-use util::is_unicast_global;
 
 const PUBLIC_KEY: [u8; 32] = [241, 1, 228, 0, 247, 163, 248, 66, 94, 57, 122, 30, 59, 183, 146, 22, 39, 145, 26, 136, 130, 145, 111, 87, 19, 2, 218, 116, 17, 82, 71, 40];
 
@@ -44,38 +41,16 @@ async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
     // console_subscriber::init();
 
-    let endpoint = Arc::new(udt::Endpoint::bind("[::]:0".parse()?)?);
-    let port = endpoint.local_addr()?.port();
-
-    let Some(addr) = local_ip_address::unix::list_afinet_netifas()?.into_iter()
-        .find_map(|(_, addr)| match addr {
-            IpAddr::V6(addr) if is_unicast_global(&addr) => Some(addr),
-            _ => None
-        }) else { bail!("no usable address :(") };
-
-    let mut mediator = MediatorClient::connect("http://[::1]:64344").await?;
-
-    let resp = mediator.rendezvous(RendezvousRequest {
-        name: "bugerking".to_owned(),
-        endpoint: Some(mediator_proto::Endpoint {
-            addr: addr.octets().to_vec(),
-            port: port.into(),
-            pubkey: Vec::new()
-        })
-    }).await?.into_inner();
-    drop(mediator);
-
-    let Some(ep) = resp.endpoint else { bail!("no endpoint??") };
-
-    let transport = SecureTransport::connect(
-        &endpoint,
-        SocketAddr::V6(SocketAddrV6::new(
-            Ipv6Addr::from(<[u8; 16]>::try_from(&ep.addr[..])?),
-            ep.port.try_into()?,
-            0, 0
-        )),
-        transport::Side::Initiator { remote_public_key: &PUBLIC_KEY }
-    ).await?;
+    // SERVER_KEY (hex) pins a key other than the bugerking's
+    let key = match std::env::var("SERVER_KEY") {
+        Ok(hex) => rendezvous::parse_key(&hex).ok_or_else(|| anyhow!("bad SERVER_KEY"))?,
+        Err(_) => PUBLIC_KEY
+    };
+    // CONNECT=host:port skips the mediator
+    let transport = match std::env::var("CONNECT") {
+        Ok(addr) => rendezvous::connect_direct(&addr, &key).await.with_context(|| format!("couldn't connect to {addr}"))?,
+        Err(_) => rendezvous::Mediator::from_env()?.connect_by_name_pinned("bugerking", &key).await?
+    };
 
     let fsys = Filesystem::new(transport).await?;
     loop {
@@ -0,0 +1,53 @@
+//! Proof that a registrant holds the private half of its static key.
+//!
+//! The keys are X25519, so there's nothing to sign with. Instead, the mediator starts a
+//! Noise NK handshake towards the claimed public key, and only its holder can finish it.
+//! The prologue binds the exchange to the name being registered.
+
+use std::io;
+
+use snow::{Builder, HandshakeState};
+
+const PATTERN: &str = "Noise_NK_25519_ChaChaPoly_BLAKE2s";
+const PROLOGUE: &[u8] = b"ninewire mediator register\0";
+
+// e, es and e, ee with empty payloads
+const MAX_MESSAGE: usize = 64;
+
+fn prologue(name: &str) -> Vec<u8> {
+    [PROLOGUE, name.as_bytes()].concat()
+}
+
+/// The mediator's side of a challenge.
+pub struct Challenge(HandshakeState);
+
+impl Challenge {
+    /// Returns the challenge to send to whoever claims `pubkey` for `name`.
+    pub fn new(name: &str, pubkey: &[u8]) -> io::Result<(Self, Vec<u8>)> {
+        let prologue = prologue(name);
+        let mut hs = Builder::new(PATTERN.parse().unwrap())
+            .prologue(&prologue).map_err(io::Error::other)?
+            .remote_public_key(pubkey).map_err(io::Error::other)?
+            .build_initiator().map_err(io::Error::other)?;
+        let mut buf = [0; MAX_MESSAGE];
+        let n = hs.write_message(&[], &mut buf).map_err(io::Error::other)?;
+        Ok((Self(hs), buf[..n].to_vec()))
+    }
+
+    pub fn verify(mut self, response: &[u8]) -> bool {
+        self.0.read_message(response, &mut []).is_ok() && self.0.is_handshake_finished()
+    }
+}
+
+/// Answers a [`Challenge`] for `name` with the registrant's private key.
+pub fn respond(name: &str, private_key: &[u8], challenge: &[u8]) -> io::Result<Vec<u8>> {
+    let prologue = prologue(name);
+    let mut hs = Builder::new(PATTERN.parse().unwrap())
+        .prologue(&prologue).map_err(io::Error::other)?
+        .local_private_key(private_key).map_err(io::Error::other)?
+        .build_responder().map_err(io::Error::other)?;
+    hs.read_message(challenge, &mut []).map_err(io::Error::other)?;
+    let mut buf = [0; MAX_MESSAGE];
+    let n = hs.write_message(&[], &mut buf).map_err(io::Error::other)?;
+    Ok(buf[..n].to_vec())
+}
@@ -1,6 +1,80 @@
 #![forbid(unsafe_code)]
 
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+pub mod challenge;
+
 tonic::include_proto!("ninewire.mediator");
 
 pub const FILE_DESCRIPTOR_SET: &[u8] =
-    tonic::include_file_descriptor_set!("mediator_descriptor");
\ No newline at end of file
+    tonic::include_file_descriptor_set!("mediator_descriptor");
+
+/// IPv4 (and IPv4-mapped) addresses are sent as 4 bytes, IPv6 as 16.
+pub fn ip_to_bytes(ip: IpAddr) -> Vec<u8> {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec()
+    }
+}
+
+pub fn ip_from_bytes(bytes: &[u8]) -> Option<IpAddr> {
+    if let Ok(octets) = <[u8; 4]>::try_from(bytes) {
+        Some(Ipv4Addr::from(octets).into())
+    } else if let Ok(octets) = <[u8; 16]>::try_from(bytes) {
+        Some(Ipv6Addr::from(octets).to_canonical())
+    } else {
+        None
+    }
+}
+
+impl Candidate {
+    pub fn new(kind: candidate::Kind, addr: SocketAddr) -> Self {
+        Self {
+            kind: kind.into(),
+            addr: ip_to_bytes(addr.ip()),
+            port: addr.port().into()
+        }
+    }
+
+    pub fn ip(&self) -> Option<IpAddr> {
+        ip_from_bytes(&self.addr)
+    }
+
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        let port = u16::try_from(self.port).ok().filter(|&port| port != 0)?;
+        Some(SocketAddr::new(self.ip()?, port))
+    }
+}
+
+impl Relay {
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        let port = u16::try_from(self.port).ok().filter(|&port| port != 0)?;
+        Some(SocketAddr::new(ip_from_bytes(&self.addr)?, port))
+    }
+}
+
+impl Endpoint {
+    /// The candidates' addresses in the order they should be tried: LAN first, so that
+    /// peers on the same network talk directly, then host, then reflexive. IPv6 goes
+    /// before IPv4 within each kind. Invalid candidates and duplicates are skipped.
+    pub fn connect_order(&self) -> Vec<SocketAddr> {
+        let mut addrs = self.candidates.iter()
+            .filter_map(|c| {
+                let rank = match c.kind() {
+                    candidate::Kind::Lan => 0,
+                    candidate::Kind::Host => 1,
+                    candidate::Kind::Reflexive => 2
+                };
+                Some((rank, c.socket_addr()?))
+            })
+            .collect::<Vec<_>>();
+        addrs.sort_by_key(|&(rank, addr)| (rank, addr.is_ipv4()));
+        let mut seen = Vec::with_capacity(addrs.len());
+        for (_, addr) in addrs {
+            if !seen.contains(&addr) {
+                seen.push(addr);
+            }
+        }
+        seen
+    }
+}
@@ -0,0 +1,76 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use anyhow::Context;
+use clap::Parser;
+use serde::Deserialize;
+
+#[derive(Debug, Parser)]
+#[command(about = "Introduces ninewire clients to servers")]
+pub struct Args {
+    /// TOML config file, with the same options as the command line (in kebab-case).
+    /// Options on the command line take precedence.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    #[command(flatten)]
+    options: Options
+}
+
+#[derive(Debug, Default, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Options {
+    /// Address to serve gRPC on [default: [::]:64344]
+    #[arg(long)]
+    pub listen: Option<SocketAddr>,
+    /// PEM certificate chain to serve TLS with
+    #[arg(long)]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for --tls-cert
+    #[arg(long)]
+    pub tls_key: Option<PathBuf>,
+    /// PEM CA certificates. If given, registering needs a client certificate signed by
+    /// one of them, while other requests still don't.
+    #[arg(long)]
+    pub client_ca: Option<PathBuf>,
+    /// Public address of the relay, which listens on the same port on all interfaces.
+    /// No relay if unset.
+    #[arg(long, env = "RELAY_ADDR")]
+    pub relay_addr: Option<SocketAddr>,
+    /// Where name bindings are kept [default: mediator.db]
+    #[arg(long, env = "STATE_DB")]
+    pub state_db: Option<PathBuf>,
+    /// Seconds after which a name whose server hasn't been seen is released [default: 30 days]
+    #[arg(long, env = "NAME_LEASE")]
+    pub name_lease: Option<u64>
+}
+
+impl Options {
+    /// Takes every option that's unset in `self` from `other`.
+    fn or(self, other: Self) -> Self {
+        Self {
+            listen: self.listen.or(other.listen),
+            tls_cert: self.tls_cert.or(other.tls_cert),
+            tls_key: self.tls_key.or(other.tls_key),
+            client_ca: self.client_ca.or(other.client_ca),
+            relay_addr: self.relay_addr.or(other.relay_addr),
+            state_db: self.state_db.or(other.state_db),
+            name_lease: self.name_lease.or(other.name_lease)
+        }
+    }
+}
+
+impl Args {
+    /// The options from the command line, falling back on the config file.
+    pub fn load(self) -> anyhow::Result<Options> {
+        let options = match self.config {
+            Some(path) => {
+                let file = std::fs::read_to_string(&path).with_context(|| format!("couldn't read {}", path.display()))?;
+                let file = toml::from_str::<Options>(&file).with_context(|| format!("couldn't parse {}", path.display()))?;
+                self.options.or(file)
+            },
+            None => self.options
+        };
+        anyhow::ensure!(options.tls_cert.is_some() == options.tls_key.is_some(), "tls-cert and tls-key go together");
+        anyhow::ensure!(options.client_ca.is_none() || options.tls_cert.is_some(), "client-ca needs TLS");
+        Ok(options)
+    }
+}
@@ -0,0 +1,48 @@
+use std::{hash::Hash, net::IpAddr, time::{Duration, Instant}};
+
+/// Allows at most `limit` hits per key in each `window`.
+#[derive(Debug)]
+pub struct RateLimiter<K: Eq + Hash> {
+    limit: u32,
+    window: Duration,
+    windows: scc::HashMap<K, (Instant, u32)>
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            windows: scc::HashMap::default()
+        }
+    }
+
+    /// Counts a hit for `key`. Returns false if it's over the limit.
+    pub async fn hit(&self, key: K) -> bool {
+        let now = Instant::now();
+        let mut ent = self.windows.entry_async(key).await.or_insert((now, 0));
+        let (start, count) = ent.get_mut();
+        if now.duration_since(*start) >= self.window {
+            (*start, *count) = (now, 0);
+        }
+        if *count >= self.limit {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    /// Forgets the keys whose window is over.
+    pub async fn sweep(&self) {
+        self.windows.retain_async(|_, (start, _)| start.elapsed() < self.window).await;
+    }
+}
+
+/// What per-address limits apply to: an IPv4 address, or an IPv6 /64, since that's what a
+/// single host usually gets.
+pub fn source(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6((u128::from(ip) & !u128::from(u64::MAX)).into()),
+        ip => ip
+    }
+}
@@ -1,68 +1,261 @@
 #![forbid(unsafe_code)]
 
-use std::{collections::HashMap, pin::Pin, sync::{Arc, LazyLock}};
+use std::{collections::HashMap, io, net::{IpAddr, SocketAddr}, pin::Pin, sync::{Arc, LazyLock}, time::Duration};
 
 use async_stream::try_stream;
-use mediator_proto::{mediator_server, register_request, Endpoint, RegisterReply, RegisterRequest, RendezvousReply, RendezvousRequest};
+use clap::Parser;
+use mediator_proto::{candidate, challenge::Challenge, ip_to_bytes, mediator_server, register_request, Candidate, DescribeRequest, Endpoint, ListServicesReply, ListServicesRequest, RegisterReply, RegisterRequest, RendezvousReply, RendezvousRequest, Service, ServiceInfo};
+use limit::RateLimiter;
 use scc::hash_map::Entry;
-use tokio::sync::{mpsc, oneshot};
+use store::Store;
+use tokio::{sync::{mpsc, oneshot, Semaphore}, time};
 use tokio_stream::Stream;
 use tokio_util::sync::CancellationToken;
-use tonic::Status;
-use util::polymur;
+use tonic::{transport::{Certificate, Identity, ServerTlsConfig}, Status};
+use udt::SocketOptions;
+use util::{addr_scope, polymur, AddrScope};
+
+mod config;
+mod limit;
+mod relay;
+mod store;
+
+/// The registrant's endpoint, and a relay session if there's one to be had
+type Approval = Option<(Endpoint, Option<mediator_proto::Relay>)>;
 
 #[derive(Debug)]
 struct PlsRendezvous {
     ep: Endpoint,
-    reply: oneshot::Sender<Option<Endpoint>>
+    // Whose relay quota a relay session would count against
+    source: Option<IpAddr>,
+    reply: oneshot::Sender<Approval>
+}
+
+/// A live registration
+#[derive(Debug, Clone)]
+struct Mapping {
+    requests: mpsc::Sender<PlsRendezvous>,
+    // Rendezvous requests waiting on the registrant
+    pending: Arc<Semaphore>
 }
 
-#[derive(Debug, Default)]
+impl Mapping {
+    fn new() -> (Self, mpsc::Receiver<PlsRendezvous>) {
+        let (requests, rx) = mpsc::channel(1);
+        (Self { requests, pending: Arc::new(Semaphore::new(MAX_PENDING)) }, rx)
+    }
+}
+
+#[derive(Debug)]
 struct Handler {
-    mappings: Arc<scc::HashMap<String, mpsc::Sender<PlsRendezvous>>>
+    mappings: Arc<scc::HashMap<String, Mapping>>,
+    store: Arc<Store>,
+    relay: Option<Arc<relay::Relay>>,
+    // Every request, per source address
+    per_ip: Arc<RateLimiter<IpAddr>>,
+    // Rendezvous requests, per name
+    per_name: Arc<RateLimiter<String>>,
+    // Whether registering needs a client certificate
+    client_auth: bool,
+    // Which observed addresses are worth handing out. Tests take loopback too.
+    scope: fn(&IpAddr) -> Option<AddrScope>
+}
+
+const MAX_NAME_LEN: usize = 64;
+const MAX_CANDIDATES: usize = 16;
+const MAX_DESCRIPTION_LEN: usize = 1024;
+const MAX_SHARES: usize = 64;
+const MAX_SHARE_LEN: usize = 255;
+// Per ListServices reply
+const MAX_LISTED: usize = 256;
+const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(10);
+// How long a registrant gets to answer a rendezvous request. Its approval policy may ask
+// a human.
+const RENDEZVOUS_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_PENDING: usize = 8;
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+// Per RATE_WINDOW
+const PER_IP_LIMIT: u32 = 120;
+const PER_NAME_LIMIT: u32 = 30;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+// A registrant is dropped after this many heartbeats go missing
+const MISSED_HEARTBEATS: u32 = 3;
+// How long a name stays bound to its key after its server was last seen
+const DEFAULT_LEASE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+fn storage_error(e: io::Error) -> Status {
+    Status::internal(format!("storage error: {e}"))
+}
+
+fn validate_name(name: &str) -> tonic::Result<()> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN || name.chars().any(char::is_control) {
+        return Err(Status::invalid_argument("bad name"));
+    }
+    Ok(())
+}
+
+fn validate_info(info: &ServiceInfo) -> tonic::Result<()> {
+    if info.description.len() > MAX_DESCRIPTION_LEN {
+        return Err(Status::invalid_argument("description is too long"));
+    }
+    if info.shares.len() > MAX_SHARES {
+        return Err(Status::invalid_argument("too many shares"));
+    }
+    for share in &info.shares {
+        if share.is_empty() || share.len() > MAX_SHARE_LEN || share.chars().any(char::is_control) {
+            return Err(Status::invalid_argument("bad share name"));
+        }
+    }
+    Ok(())
 }
 
 fn validate_endpoint(ep: &Endpoint, server: bool) -> tonic::Result<()> {
-    if ep.addr.len() != 16 {
-        return Err(Status::invalid_argument("bad address"));
+    if ep.candidates.is_empty() {
+        return Err(Status::invalid_argument("no candidates"));
     }
-    if !(1..65536).contains(&ep.port) {
-        return Err(Status::invalid_argument("bad port"));
+    if ep.candidates.len() > MAX_CANDIDATES {
+        return Err(Status::invalid_argument("too many candidates"));
     }
-    if !server && !ep.pubkey.is_empty() {
-        return Err(Status::invalid_argument("you don't get a public key"));
+    for c in &ep.candidates {
+        if candidate::Kind::try_from(c.kind).is_err() {
+            return Err(Status::invalid_argument("bad candidate kind"));
+        }
+        if !c.ip().is_some_and(|ip| !ip.is_unspecified() && !ip.is_multicast()) {
+            return Err(Status::invalid_argument("bad address"));
+        }
+        if !(1..65536).contains(&c.port) {
+            return Err(Status::invalid_argument("bad port"));
+        }
     }
-    if server && ep.pubkey.len() != 32 {
+    // A client's key is optional, and only a claim for the server's approval policy
+    if !(ep.pubkey.len() == 32 || !server && ep.pubkey.is_empty()) {
         return Err(Status::invalid_argument("bad public key"))
     }
     Ok(())
 }
 
+/// Adds `ip` as a reflexive candidate on each port the peer advertised. Reflexive
+/// candidates are ours to make, and servers' policies trust them, so any the peer
+/// claimed itself are dropped first.
+fn add_reflexive(ep: &mut Endpoint, ip: Option<IpAddr>) {
+    ep.candidates.retain(|c| c.kind() != candidate::Kind::Reflexive);
+    let Some(ip) = ip else { return };
+    let mut ports = ep.candidates.iter().map(|c| c.port).collect::<Vec<_>>();
+    ports.sort_unstable();
+    ports.dedup();
+    // At most one per claimed candidate, so there's always room
+    for port in ports {
+        let Ok(port) = u16::try_from(port) else { continue };
+        ep.candidates.push(Candidate::new(candidate::Kind::Reflexive, SocketAddr::new(ip, port)));
+    }
+}
+
+impl Handler {
+    /// STUN-style: the address a request came from, if it's worth handing out. This is
+    /// the address of the peer's TCP connection to us, so it only tells us the IP; the
+    /// port of its UDT socket is anyone's guess, so we assume its NAT preserves it.
+    fn observed_ip(&self, remote: Option<SocketAddr>) -> Option<IpAddr> {
+        let ip = remote?.ip().to_canonical();
+        (self.scope)(&ip).map(|_| ip)
+    }
+
+    fn is_online(&self, name: &str) -> bool {
+        self.mappings.read_sync(name, |_, m| !m.requests.is_closed()).unwrap_or(false)
+    }
+
+    async fn limit_source<T>(&self, req: &tonic::Request<T>) -> tonic::Result<()> {
+        if let Some(addr) = req.remote_addr() && !self.per_ip.hit(limit::source(addr.ip())).await {
+            return Err(Status::resource_exhausted("too many requests"));
+        }
+        Ok(())
+    }
+}
+
 #[tonic::async_trait]
 impl mediator_server::Mediator for Handler {
     type RegisterStream = Pin<Box<dyn Stream<Item = tonic::Result<RegisterReply>> + Send>>;//Either<ReceiverStream<tonic::Result<RegisterReply>>, Empty<tonic::Result<RegisterReply>>>;
 
     async fn rendezvous(&self, req: tonic::Request<RendezvousRequest>) -> tonic::Result<tonic::Response<RendezvousReply>> {
+        self.limit_source(&req).await?;
+        let observed = self.observed_ip(req.remote_addr());
+        let source = req.remote_addr().map(|addr| limit::source(addr.ip()));
         let req = req.into_inner();
-        let Some(ep) = req.endpoint else { return Err(Status::invalid_argument("unspecified endpoint")) };
+        validate_name(&req.name)?;
+        let Some(mut ep) = req.endpoint else { return Err(Status::invalid_argument("unspecified endpoint")) };
         validate_endpoint(&ep, false)?;
-        let Some(pls_sender) = self.mappings.get_async(&req.name).await else { return Err(Status::not_found("unknown name")) };
+        add_reflexive(&mut ep, observed);
+        if !self.per_name.hit(req.name.clone()).await {
+            return Err(Status::resource_exhausted("too many requests for this name"));
+        }
+        // A name that's bound but not registered right now belongs to a server that went
+        // away, and might come back.
+        let offline = || match self.store.get(&req.name) {
+            Ok(Some(_)) => Status::unavailable("temporarily offline"),
+            Ok(None) => Status::not_found("unknown name"),
+            Err(e) => storage_error(e)
+        };
+        let Some(mapping) = self.mappings.read_async(&req.name, |_, m| m.clone()).await else { return Err(offline()) };
+        let _permit = mapping.pending.try_acquire_owned()
+            .map_err(|_| Status::resource_exhausted("too many pending requests for this name"))?;
         let (pls_req, pls_rep) = oneshot::channel();
-        pls_sender.send(PlsRendezvous {
-            ep,
-            reply: pls_req
-        }).await.map_err(|_| Status::internal("mapping is broken"))?;
+        let rep = time::timeout(RENDEZVOUS_TIMEOUT, async {
+            mapping.requests.send(PlsRendezvous {
+                ep,
+                source,
+                reply: pls_req
+            }).await.map_err(|_| offline())?;
+            pls_rep.await.map_err(|_| Status::unavailable("no reply from peer"))
+        }).await.map_err(|_| Status::deadline_exceeded("peer didn't answer in time"))??;
 
-        let rep = pls_rep.await.map_err(|_| Status::unavailable("no reply from peer"))?;
-
-        let remote_ep = rep.ok_or_else(|| Status::permission_denied("rendezvous denied by peer"))?;
+        let (remote_ep, relay) = rep.ok_or_else(|| Status::permission_denied("rendezvous denied by peer"))?;
         Ok(RendezvousReply {
-            endpoint: Some(remote_ep)
+            endpoint: Some(remote_ep),
+            observed_addr: observed.map(ip_to_bytes).unwrap_or_default(),
+            relay
         }.into())
     }
 
+    async fn list_services(&self, req: tonic::Request<ListServicesRequest>) -> tonic::Result<tonic::Response<ListServicesReply>> {
+        self.limit_source(&req).await?;
+        let req = req.into_inner();
+        if req.prefix.len() > MAX_NAME_LEN {
+            return Err(Status::invalid_argument("prefix is too long"));
+        }
+        let mut services = Vec::new();
+        for ent in self.store.scan(&req.prefix) {
+            let (name, record) = ent.map_err(storage_error)?;
+            let online = self.is_online(&name);
+            if req.online_only && !online {
+                continue;
+            }
+            services.push(Service { name, info: record.info, online });
+            if services.len() >= MAX_LISTED {
+                break;
+            }
+        }
+        Ok(ListServicesReply { services }.into())
+    }
+
+    async fn describe(&self, req: tonic::Request<DescribeRequest>) -> tonic::Result<tonic::Response<Service>> {
+        self.limit_source(&req).await?;
+        let name = req.into_inner().name;
+        validate_name(&name)?;
+        let Some(record) = self.store.get(&name).map_err(storage_error)? else { return Err(Status::not_found("unknown name")) };
+        let online = self.is_online(&name);
+        Ok(Service { name, info: record.info, online }.into())
+    }
+
     async fn register(&self, req: tonic::Request<tonic::Streaming<RegisterRequest>>) -> tonic::Result<tonic::Response<Self::RegisterStream>> {
+        self.limit_source(&req).await?;
+        // The TLS layer already checked it against the CA
+        if self.client_auth && req.peer_certs().is_none_or(|certs| certs.is_empty()) {
+            return Err(Status::unauthenticated("registering needs a client certificate"));
+        }
         let mappings = self.mappings.clone();
+        let store = self.store.clone();
+        let relay = self.relay.clone();
+        let observed = self.observed_ip(req.remote_addr());
         Ok(tonic::Response::new(Box::pin(try_stream! {
             let mut req = req.into_inner();
 
@@ -70,70 +263,134 @@ impl mediator_server::Mediator for Handler {
             let Some(register_request::Req::Registration(reg)) = first.req else { Err(Status::invalid_argument("first request must be registration"))? };
 
             let name = reg.name;
-            let Some(endpoint) = reg.endpoint else { Err(Status::invalid_argument("unspecified endpoint"))? };
+            validate_name(&name)?;
+            let Some(mut endpoint) = reg.endpoint else { Err(Status::invalid_argument("unspecified endpoint"))? };
             validate_endpoint(&endpoint, true)?;
+            add_reflexive(&mut endpoint, observed);
+            if let Some(info) = &reg.info {
+                validate_info(info)?;
+            }
+
+            // A name belongs to the first key that registers it, and every registration
+            // has to prove it holds that key.
+            if store.get(&name).map_err(storage_error)?.is_some_and(|r| r.pubkey != endpoint.pubkey) {
+                Err(Status::permission_denied("name is bound to a different key"))?
+            }
+            let (challenge, challenge_msg) = Challenge::new(&name, &endpoint.pubkey)
+                .map_err(|_| Status::invalid_argument("bad public key"))?;
+            yield RegisterReply {
+                request_id: 0,
+                challenge: challenge_msg,
+                ..Default::default()
+            };
+            let response = time::timeout(CHALLENGE_TIMEOUT, req.message()).await
+                .map_err(|_| Status::deadline_exceeded("no challenge response"))??;
+            let Some(RegisterRequest { req: Some(register_request::Req::ChallengeResponse(response)) }) = response else {
+                Err(Status::invalid_argument("expected a challenge response"))?
+            };
+            if !challenge.verify(&response) {
+                Err(Status::unauthenticated("challenge failed"))?
+            }
+            if !store.bind(&name, &endpoint.pubkey, reg.info).await.map_err(storage_error)? {
+                Err(Status::permission_denied("name is bound to a different key"))?
+            }
 
             let ent = mappings.entry_async(name.clone()).await;
             let mut rdv_requests = match ent {
                 Entry::Occupied(mut ent) => {
-                    if !ent.is_closed() {
+                    if !ent.requests.is_closed() {
                         Err(Status::already_exists("requested name is in use"))?
                     }
-                    let (inc, rdv_request) = mpsc::channel(1);
-                    ent.insert(inc);
-                    rdv_request
+                    let (mapping, rdv_requests) = Mapping::new();
+                    ent.insert(mapping);
+                    rdv_requests
                 },
                 Entry::Vacant(ent) => {
-                    let (inc, rdv_request) = mpsc::channel(1);
-                    ent.insert_entry(inc);
-                    rdv_request
+                    let (mapping, rdv_requests) = Mapping::new();
+                    ent.insert_entry(mapping);
+                    rdv_requests
                 }
             };
 
             println!("new binding for {name}");
 
+            yield RegisterReply {
+                request_id: 0,
+                observed_addr: observed.map(ip_to_bytes).unwrap_or_default(),
+                heartbeat_interval: HEARTBEAT_INTERVAL.as_secs() as u32,
+                ..Default::default()
+            };
+
+            let mut deadline = time::Instant::now() + HEARTBEAT_INTERVAL * MISSED_HEARTBEATS;
             let mut ctr = 1u64;
-            let mut inflight = HashMap::<u64, oneshot::Sender<Option<Endpoint>>, polymur::RandomState>::default();
+            let mut inflight = HashMap::<u64, (oneshot::Sender<Approval>, Option<IpAddr>), polymur::RandomState>::default();
 
             loop {
-                tokio::select! {
+                // try_stream! can't see a ? inside select!, so errors are passed out of it
+                let res: tonic::Result<()> = tokio::select! {
                     rdv_req = rdv_requests.recv() => {
                         match rdv_req {
                             None => break,
                             Some(rdv_req) => {
                                 let id = ctr;
                                 ctr += 1;
-                                inflight.insert(id, rdv_req.reply);
+                                inflight.insert(id, (rdv_req.reply, rdv_req.source));
                                 yield RegisterReply {
                                     request_id: id,
-                                    endpoint: Some(rdv_req.ep)
+                                    endpoint: Some(rdv_req.ep),
+                                    ..Default::default()
                                 };
+                                Ok(())
                             }
                         }
                     }
+                    _ = time::sleep_until(deadline) => Err(Status::deadline_exceeded("missed heartbeats")),
                     rdv_reply = req.message() => {
-                        let rdv_reply = rdv_reply.unwrap(); // todo
+                        let rdv_reply = match rdv_reply {
+                            Ok(rdv_reply) => rdv_reply,
+                            Err(e) => {
+                                println!("registration for {name} failed: {e}");
+                                break;
+                            }
+                        };
                         match rdv_reply {
                             None => break,
                             Some(rdv_reply) => {
                                 match rdv_reply.req {
                                     Some(register_request::Req::ApproveId(id)) => {
-                                        if let Some(reply) = inflight.remove(&id) {
-                                            let _ = reply.send(Some(endpoint.clone()));
+                                        if let Some((reply, source)) = inflight.remove(&id) {
+                                            // Only now that it's wanted, and only if the requester is still there
+                                            let relay = match (&relay, source) {
+                                                (Some(relay), Some(source)) if !reply.is_closed() => relay.offer(source),
+                                                _ => None
+                                            };
+                                            let _ = reply.send(Some((endpoint.clone(), relay.clone())));
+                                            yield RegisterReply {
+                                                request_id: id,
+                                                relay,
+                                                ..Default::default()
+                                            };
                                         }
+                                        Ok(())
                                     },
                                     Some(register_request::Req::DenyId(id)) => {
-                                        if let Some(reply) = inflight.remove(&id) {
+                                        if let Some((reply, _)) = inflight.remove(&id) {
                                             let _ = reply.send(None);
                                         }
+                                        Ok(())
+                                    },
+                                    Some(register_request::Req::Heartbeat(_)) => {
+                                        deadline = time::Instant::now() + HEARTBEAT_INTERVAL * MISSED_HEARTBEATS;
+                                        store.renew(&name, &endpoint.pubkey).map_err(storage_error)
                                     }
-                                    _ => ()
+                                    _ => Ok(())
                                 }
                             }
                         }
                     }
-                }
-                inflight.retain(|_, v| !v.is_closed());
+                };
+                res?;
+                inflight.retain(|_, (reply, _)| !reply.is_closed());
             }
         })))
     }
@@ -143,16 +400,185 @@ static SHUTDOWN: LazyLock<CancellationToken> = LazyLock::new(CancellationToken::
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let options = config::Args::parse().load()?;
     ctrlc::set_handler(|| SHUTDOWN.cancel())?;
 
-    let addr = "[::]:64344".parse()?;
-    println!("listening on {addr}");
-    tonic::transport::Server::builder()
+    let relay = match options.relay_addr {
+        Some(public_addr) => {
+            let endpoint = udt::Endpoint::bind_with_options(
+                SocketAddr::new("::".parse()?, public_addr.port()),
+                SocketOptions::new().ipv6_only(false)
+            )?;
+            let listener = endpoint.listen_datagram(64)?;
+            let relay = Arc::new(relay::Relay::new(public_addr));
+            println!("relaying on {public_addr}");
+            tokio::spawn(relay.clone().serve(listener));
+            Some(relay)
+        },
+        None => None
+    };
+
+    let lease = options.name_lease.map_or(DEFAULT_LEASE, Duration::from_secs);
+    anyhow::ensure!(lease >= HEARTBEAT_INTERVAL * MISSED_HEARTBEATS, "name lease is shorter than the heartbeat timeout");
+    let store = Arc::new(Store::open(options.state_db.as_deref().unwrap_or("mediator.db".as_ref()), lease)?);
+    tokio::spawn({
+        let store = store.clone();
+        async move {
+            let mut interval = time::interval(EXPIRY_INTERVAL.min(lease));
+            loop {
+                interval.tick().await;
+                match store.expire() {
+                    Ok(0) => (),
+                    Ok(n) => println!("{n} name(s) expired"),
+                    Err(e) => println!("couldn't expire names: {e}")
+                }
+            }
+        }
+    });
+
+    let per_ip = Arc::new(RateLimiter::new(PER_IP_LIMIT, RATE_WINDOW));
+    let per_name = Arc::new(RateLimiter::new(PER_NAME_LIMIT, RATE_WINDOW));
+    tokio::spawn({
+        let (per_ip, per_name) = (per_ip.clone(), per_name.clone());
+        async move {
+            let mut interval = time::interval(RATE_WINDOW);
+            loop {
+                interval.tick().await;
+                per_ip.sweep().await;
+                per_name.sweep().await;
+            }
+        }
+    });
+
+    let mut server = tonic::transport::Server::builder();
+    if let (Some(cert), Some(key)) = (&options.tls_cert, &options.tls_key) {
+        let mut tls = ServerTlsConfig::new()
+            .identity(Identity::from_pem(std::fs::read(cert)?, std::fs::read(key)?));
+        if let Some(ca) = &options.client_ca {
+            // Only registering needs one, see Handler::register
+            tls = tls.client_ca_root(Certificate::from_pem(std::fs::read(ca)?))
+                .client_auth_optional(true);
+        }
+        server = server.tls_config(tls)?;
+    }
+
+    let addr = options.listen.unwrap_or_else(|| "[::]:64344".parse().unwrap());
+    println!("listening on {addr}{}", if options.tls_cert.is_some() { " (TLS)" } else { "" });
+    server
         .add_service(tonic_reflection::server::Builder::configure()
             .register_encoded_file_descriptor_set(mediator_proto::FILE_DESCRIPTOR_SET)
             .build_v1()?)
-        .add_service(mediator_server::MediatorServer::new(Handler::default()))
+        .add_service(mediator_server::MediatorServer::new(Handler {
+            mappings: Default::default(),
+            store,
+            relay,
+            per_ip,
+            per_name,
+            client_auth: options.client_ca.is_some(),
+            scope: addr_scope
+        }))
         .serve_with_shutdown(addr, SHUTDOWN.cancelled()).await?;
     println!("shutting down");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use mediator_proto::{mediator_client::MediatorClient, register_request::Req, Registration};
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+    use tonic::transport::Channel;
+
+    use super::*;
+
+    const LOOPBACK: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn with_loopback(ip: &IpAddr) -> Option<AddrScope> {
+        addr_scope(ip).or(ip.is_loopback().then_some(AddrScope::Lan))
+    }
+
+    async fn serve(db: &std::path::Path) -> MediatorClient<Channel> {
+        let handler = Handler {
+            mappings: Default::default(),
+            store: Arc::new(Store::open(db, DEFAULT_LEASE).unwrap()),
+            // Never served, but it hands out sessions all the same
+            relay: Some(Arc::new(relay::Relay::new(SocketAddr::new(LOOPBACK, 9)))),
+            per_ip: Arc::new(RateLimiter::new(PER_IP_LIMIT, RATE_WINDOW)),
+            per_name: Arc::new(RateLimiter::new(PER_NAME_LIMIT, RATE_WINDOW)),
+            client_auth: false,
+            scope: with_loopback
+        };
+        let listener = TcpListener::bind((LOOPBACK, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(tonic::transport::Server::builder()
+            .add_service(mediator_server::MediatorServer::new(handler))
+            .serve_with_incoming(TcpListenerStream::new(listener)));
+        MediatorClient::connect(format!("http://{addr}")).await.unwrap()
+    }
+
+    fn endpoint(port: u16, pubkey: Vec<u8>) -> Endpoint {
+        Endpoint {
+            pubkey,
+            candidates: vec![Candidate::new(candidate::Kind::Lan, SocketAddr::new([10, 0, 0, 1].into(), port))]
+        }
+    }
+
+    fn reflexive(ep: &Endpoint) -> Vec<SocketAddr> {
+        ep.candidates.iter()
+            .filter(|c| c.kind() == candidate::Kind::Reflexive)
+            .filter_map(Candidate::socket_addr)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn rendezvous_hands_out_observed_addresses_and_relays() {
+        let db = std::env::temp_dir().join(format!("mediator-test-{}", std::process::id()));
+        let mut client = serve(&db).await;
+        let keys = snow::Builder::new("Noise_NK_25519_ChaChaPoly_BLAKE2s".parse().unwrap()).generate_keypair().unwrap();
+
+        let (requests, rx) = mpsc::channel(4);
+        requests.send(RegisterRequest {
+            req: Some(Req::Registration(Registration {
+                name: "test".into(),
+                endpoint: Some(endpoint(5000, keys.public.clone())),
+                info: None
+            }))
+        }).await.unwrap();
+        let mut replies = client.register(ReceiverStream::new(rx)).await.unwrap().into_inner();
+        let challenge = replies.message().await.unwrap().unwrap().challenge;
+        let response = mediator_proto::challenge::respond("test", &keys.private, &challenge).unwrap();
+        requests.send(RegisterRequest { req: Some(Req::ChallengeResponse(response)) }).await.unwrap();
+        let accepted = replies.message().await.unwrap().unwrap();
+        assert_eq!(accepted.observed_addr, ip_to_bytes(LOOPBACK));
+
+        let rendezvous = tokio::spawn({
+            let mut client = client.clone();
+            async move {
+                // Claiming to be somewhere else gets it nowhere
+                let mut ep = endpoint(6000, Vec::new());
+                ep.candidates.push(Candidate::new(candidate::Kind::Reflexive, SocketAddr::new([203, 0, 113, 9].into(), 6000)));
+                client.rendezvous(RendezvousRequest { name: "test".into(), endpoint: Some(ep) }).await
+            }
+        });
+
+        // The server sees the client's observed address, on the client's port
+        let request = replies.message().await.unwrap().unwrap();
+        assert_eq!(reflexive(&request.endpoint.unwrap()), [SocketAddr::new(LOOPBACK, 6000)]);
+        // No relay session until the server says yes
+        assert!(request.relay.is_none());
+        requests.send(RegisterRequest { req: Some(Req::ApproveId(request.request_id)) }).await.unwrap();
+        let approved = replies.message().await.unwrap().unwrap();
+        assert_eq!(approved.request_id, request.request_id);
+        assert!(approved.endpoint.is_none());
+
+        // And the client sees the server's, on the server's
+        let reply = rendezvous.await.unwrap().unwrap().into_inner();
+        assert_eq!(reply.observed_addr, ip_to_bytes(LOOPBACK));
+        assert_eq!(reflexive(&reply.endpoint.unwrap()), [SocketAddr::new(LOOPBACK, 5000)]);
+        assert_eq!(reply.relay.unwrap().token, approved.relay.unwrap().token);
+
+        let _ = std::fs::remove_dir_all(db);
+    }
+}
@@ -0,0 +1,162 @@
+use std::{io, net::{IpAddr, SocketAddr}, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::{Duration, Instant}};
+
+use tokio::{sync::{OwnedSemaphorePermit, Semaphore}, time};
+use udt::{Connection, Listener};
+
+const TOKEN_LEN: usize = 16;
+type Token = [u8; TOKEN_LEN];
+
+// Quotas. Relayed traffic costs us bandwidth on both legs, so every session is capped.
+const MAX_SESSIONS: usize = 256;
+// Per requesting source (see limit::source), so no one requester can take them all
+const MAX_SESSIONS_PER_SOURCE: usize = 4;
+const MAX_BANDWIDTH: u64 = 1 << 20;
+const MAX_BYTES: u64 = 1 << 30;
+
+// How long after the offer both peers have to show up
+const PAIR_TIMEOUT: Duration = Duration::from_secs(60);
+// How long a new connection has to present its token
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+const PAIRED: u8 = 1;
+
+#[derive(Debug)]
+struct Session {
+    created: Instant,
+    _permit: OwnedSemaphorePermit,
+    _quota: SourceQuota,
+    // The first peer to show up waits here for the second
+    waiting: Option<Connection>
+}
+
+/// A session's place in its requester's quota, given back when the session ends.
+#[derive(Debug)]
+struct SourceQuota {
+    sessions: Arc<scc::HashMap<IpAddr, usize>>,
+    source: IpAddr
+}
+
+impl Drop for SourceQuota {
+    fn drop(&mut self) {
+        if let scc::hash_map::Entry::Occupied(mut ent) = self.sessions.entry_sync(self.source) {
+            *ent.get_mut() -= 1;
+            if *ent.get() == 0 {
+                let _ = ent.remove_entry();
+            }
+        }
+    }
+}
+
+/// A TURN-like relay that pairs two UDT connections by token and forwards datagrams
+/// between them. The datagrams are SecureTransport messages, so it never sees plaintext.
+#[derive(Debug)]
+pub struct Relay {
+    public_addr: SocketAddr,
+    sessions: scc::HashMap<Token, Session>,
+    permits: Arc<Semaphore>,
+    per_source: Arc<scc::HashMap<IpAddr, usize>>
+}
+
+impl Relay {
+    pub fn new(public_addr: SocketAddr) -> Self {
+        Self {
+            public_addr,
+            sessions: scc::HashMap::default(),
+            permits: Arc::new(Semaphore::new(MAX_SESSIONS)),
+            per_source: Arc::default()
+        }
+    }
+
+    /// Sets up a session for one rendezvous requested from `source`. Returns `None` if
+    /// the relay is full, or `source` has too many sessions already.
+    pub fn offer(&self, source: IpAddr) -> Option<mediator_proto::Relay> {
+        let permit = self.permits.clone().try_acquire_owned().ok()?;
+        {
+            let mut count = self.per_source.entry_sync(source).or_insert(0);
+            if *count.get() >= MAX_SESSIONS_PER_SOURCE {
+                return None;
+            }
+            *count.get_mut() += 1;
+        }
+        let quota = SourceQuota { sessions: self.per_source.clone(), source };
+        let token = rand::random::<Token>();
+        self.sessions.insert_sync(token, Session {
+            created: Instant::now(),
+            _permit: permit,
+            _quota: quota,
+            waiting: None
+        }).ok()?;
+        Some(mediator_proto::Relay {
+            addr: mediator_proto::ip_to_bytes(self.public_addr.ip()),
+            port: self.public_addr.port().into(),
+            token: token.to_vec()
+        })
+    }
+
+    pub async fn serve(self: Arc<Self>, listener: Listener) -> io::Result<()> {
+        let mut sweep = time::interval(PAIR_TIMEOUT / 4);
+        loop {
+            tokio::select! {
+                conn = listener.accept() => {
+                    let conn = conn?;
+                    let this = self.clone();
+                    tokio::spawn(async move {
+                        let peer = conn.peer_addr();
+                        if let Err(e) = this.handle(conn).await {
+                            println!("relay connection from {peer:?} failed: {e}");
+                        }
+                    });
+                },
+                _ = sweep.tick() => {
+                    // Dropping a session closes any connection still waiting in it.
+                    self.sessions.retain_async(|_, s| s.created.elapsed() < PAIR_TIMEOUT).await;
+                }
+            }
+        }
+    }
+
+    async fn handle(&self, conn: Connection) -> io::Result<()> {
+        let mut token = Token::default();
+        let n = time::timeout(HELLO_TIMEOUT, conn.recv(&mut token)).await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        if n != TOKEN_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad token"));
+        }
+
+        let Some(mut ent) = self.sessions.get_async(&token).await else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "unknown token"));
+        };
+        let Some(partner) = ent.waiting.take() else {
+            ent.waiting = Some(conn);
+            return Ok(());
+        };
+        let session = ent.remove();
+
+        forward(partner, conn).await?;
+        drop(session);
+        Ok(())
+    }
+}
+
+async fn forward(a: Connection, b: Connection) -> io::Result<()> {
+    for c in [&a, &b] {
+        c.set_max_bandwidth(Some(MAX_BANDWIDTH))?;
+        c.send(&[PAIRED]).await?;
+    }
+    let quota = AtomicU64::new(MAX_BYTES);
+    // Either side closing (or misbehaving) ends the session for both.
+    tokio::try_join!(pipe(&a, &b, &quota), pipe(&b, &a, &quota))?;
+    Ok(())
+}
+
+async fn pipe(from: &Connection, to: &Connection, quota: &AtomicU64) -> io::Result<()> {
+    let mut buf = vec![0; 65536];
+    loop {
+        let n = time::timeout(IDLE_TIMEOUT, from.recv(&mut buf)).await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        quota.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |q| q.checked_sub(n as u64))
+            .map_err(|_| io::Error::new(io::ErrorKind::QuotaExceeded, "relay quota exceeded"))?;
+        to.send(&buf[..n]).await?;
+    }
+}
@@ -0,0 +1,123 @@
+use std::{io, path::Path, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use mediator_proto::ServiceInfo;
+
+/// What the mediator remembers about a name, across restarts.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Record {
+    /// The key that owns the name. The first key to register a name keeps it until its
+    /// lease runs out.
+    #[prost(bytes = "vec", tag = "1")]
+    pub pubkey: Vec<u8>,
+    /// When the owner last registered or sent a heartbeat, in seconds since the epoch
+    #[prost(uint64, tag = "2")]
+    pub last_seen: u64,
+    /// What the owner published with its last registration
+    #[prost(message, optional, tag = "3")]
+    pub info: Option<ServiceInfo>
+}
+
+/// Name ownership, persisted in a sled database.
+///
+/// A name is leased to its key: every registration and heartbeat renews the lease, and a
+/// name whose owner hasn't been seen for the whole lease is forgotten, so anyone can
+/// register it again.
+#[derive(Debug)]
+pub struct Store {
+    names: sled::Tree,
+    lease: Duration
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn decode(bytes: &[u8]) -> io::Result<Record> {
+    prost::Message::decode(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+impl Store {
+    pub fn open(path: impl AsRef<Path>, lease: Duration) -> io::Result<Self> {
+        let db = sled::open(path)?;
+        Ok(Self {
+            names: db.open_tree("names")?,
+            lease
+        })
+    }
+
+    fn is_expired(&self, record: &Record) -> bool {
+        now().saturating_sub(record.last_seen) > self.lease.as_secs()
+    }
+
+    /// The record for `name`, unless its lease has run out.
+    pub fn get(&self, name: &str) -> io::Result<Option<Record>> {
+        let Some(bytes) = self.names.get(name)? else { return Ok(None) };
+        let record = decode(&bytes)?;
+        Ok((!self.is_expired(&record)).then_some(record))
+    }
+
+    /// Records that match `prefix` and whose lease hasn't run out, in order of name.
+    pub fn scan(&self, prefix: &str) -> impl Iterator<Item = io::Result<(String, Record)>> + '_ {
+        self.names.scan_prefix(prefix)
+            .map(|ent| {
+                let (name, bytes) = ent?;
+                let name = String::from_utf8(name.to_vec()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                Ok((name, decode(&bytes)?))
+            })
+            .filter(|ent| !ent.as_ref().is_ok_and(|(_, record)| self.is_expired(record)))
+    }
+
+    /// Binds `name` to `pubkey` if it's free (or its lease ran out), and renews the lease.
+    /// Returns whether `name` belongs to `pubkey` now.
+    pub async fn bind(&self, name: &str, pubkey: &[u8], info: Option<ServiceInfo>) -> io::Result<bool> {
+        let mut current = self.names.get(name)?;
+        loop {
+            if let Some(bytes) = &current {
+                let record = decode(bytes)?;
+                if record.pubkey != pubkey && !self.is_expired(&record) {
+                    return Ok(false);
+                }
+            }
+            let new = prost::Message::encode_to_vec(&Record {
+                pubkey: pubkey.to_vec(),
+                last_seen: now(),
+                info: info.clone()
+            });
+            match self.names.compare_and_swap(name, current, Some(new))? {
+                Ok(()) => break,
+                Err(e) => current = e.current
+            }
+        }
+        self.names.flush_async().await?;
+        Ok(true)
+    }
+
+    /// Renews the lease on `name`, if it's still bound to `pubkey`.
+    pub fn renew(&self, name: &str, pubkey: &[u8]) -> io::Result<()> {
+        self.names.fetch_and_update(name, |bytes| {
+            let bytes = bytes?;
+            let Ok(mut record) = decode(bytes) else { return Some(bytes.to_vec()) };
+            if record.pubkey == pubkey {
+                record.last_seen = now();
+            }
+            Some(prost::Message::encode_to_vec(&record))
+        })?;
+        Ok(())
+    }
+
+    /// Forgets every name whose lease has run out. Returns how many there were.
+    pub fn expire(&self) -> io::Result<usize> {
+        let mut expired = 0;
+        for ent in self.names.iter() {
+            let (name, bytes) = ent?;
+            if decode(&bytes).is_ok_and(|record| !self.is_expired(&record)) {
+                continue;
+            }
+            // Unless it was renewed in the meantime
+            if self.names.compare_and_swap(&name, Some(bytes), None::<&[u8]>)?.is_ok() {
+                expired += 1;
+            }
+        }
+        Ok(expired)
+    }
+}
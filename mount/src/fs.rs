@@ -0,0 +1,669 @@
+use std::{collections::HashMap, ffi::OsString, io, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use bytes::Bytes;
+use client::{Directory, File, OpenOptions, StatChanges};
+use futures::{stream::FuturesOrdered, StreamExt as _};
+use nix::{errno::Errno, fcntl::OFlag};
+use npwire::{Stat, DMDIR};
+use tracing::{debug, warn};
+
+use crate::fuse::{self, Attr, DirBuf, Op, Request, Session, ROOT_ID};
+
+// How long the kernel may cache names and attributes
+const TTL: Duration = Duration::from_secs(1);
+const MAX_NAME_LEN: u32 = 255;
+
+// Errors the server sends by their strerror text
+const KNOWN_ERRORS: [Errno; 12] = [
+    Errno::ENOENT, Errno::EACCES, Errno::EPERM, Errno::EEXIST, Errno::ENOTDIR, Errno::EISDIR,
+    Errno::ENOTEMPTY, Errno::EINVAL, Errno::ENOSPC, Errno::EROFS, Errno::ENOSYS, Errno::ENAMETOOLONG
+];
+
+fn errno(e: io::Error) -> Errno {
+    if let Some(code) = e.raw_os_error() {
+        return Errno::from_raw(code);
+    }
+    match e.kind() {
+        io::ErrorKind::NotFound => Errno::ENOENT,
+        io::ErrorKind::PermissionDenied => Errno::EACCES,
+        io::ErrorKind::NotADirectory => Errno::ENOTDIR,
+        io::ErrorKind::IsADirectory => Errno::EISDIR,
+        io::ErrorKind::AlreadyExists => Errno::EEXIST,
+        io::ErrorKind::DirectoryNotEmpty => Errno::ENOTEMPTY,
+        io::ErrorKind::InvalidInput => Errno::EINVAL,
+        io::ErrorKind::TimedOut => Errno::ETIMEDOUT,
+        io::ErrorKind::ConnectionAborted => Errno::ECONNABORTED,
+        _ => {
+            let msg = e.to_string().to_lowercase();
+            KNOWN_ERRORS.into_iter()
+                .find(|errno| msg.starts_with(&errno.desc().to_lowercase()))
+                .unwrap_or(Errno::EIO)
+        }
+    }
+}
+
+// What readdir reports for nodes that haven't been looked up yet
+const UNKNOWN_INO: u64 = 0xffff_ffff;
+
+/// Something the kernel has looked up, until it forgets it.
+struct Node {
+    /// The directory it was found in and its name there, or `None` for the root
+    parent: Option<(Arc<Directory>, String)>,
+    /// Its own fid, if it's a directory. Files are only walked to when they're used.
+    dir: Option<Arc<Directory>>,
+    qid_path: u64,
+    nlookup: u64
+}
+
+/// Nodes by inode number, which we hand out, and by qid path, which the server keeps
+/// unique per file.
+struct Nodes {
+    by_ino: HashMap<u64, Node>,
+    by_qid: HashMap<u64, u64>,
+    next_ino: u64
+}
+
+#[derive(Debug)]
+struct DirEntry {
+    ino: u64,
+    kind: u32,
+    name: String
+}
+
+enum Handle {
+    File(Arc<File>),
+    /// Read all at once on opendir, so offsets stay put
+    Dir(Arc<Vec<DirEntry>>)
+}
+
+/// Serves a 9P tree to the kernel, one FUSE request at a time.
+pub struct NineFs {
+    nodes: Mutex<Nodes>,
+    handles: Mutex<HashMap<u64, Handle>>,
+    next_fh: AtomicU64,
+    uid: u32,
+    gid: u32
+}
+
+impl NineFs {
+    /// Everything shows up as owned by `uid` and `gid`, since 9P owners are names.
+    pub async fn new(root: Directory, uid: u32, gid: u32) -> io::Result<Self> {
+        let qid_path = root.stat().await?.qid.path;
+        let root = Node { parent: None, dir: Some(Arc::new(root)), qid_path, nlookup: 1 };
+        Ok(Self {
+            nodes: Mutex::new(Nodes {
+                by_ino: [(ROOT_ID, root)].into_iter().collect(),
+                by_qid: [(qid_path, ROOT_ID)].into_iter().collect(),
+                next_ino: ROOT_ID + 1
+            }),
+            handles: Mutex::default(),
+            next_fh: AtomicU64::new(1),
+            uid,
+            gid
+        })
+    }
+
+    pub async fn handle(&self, session: &Session, req: Request) {
+        let res = match req.op {
+            Op::Forget { nlookup } => return self.forget(req.nodeid, nlookup),
+            Op::BatchForget { nodes } => {
+                for (ino, nlookup) in nodes {
+                    self.forget(ino, nlookup);
+                }
+                return;
+            },
+            Op::Interrupt => return,
+            op => self.dispatch(req.nodeid, op).await
+        };
+        if let Err(e) = session.reply(req.unique, res.as_deref().map_err(|&e| e)) {
+            warn!("couldn't reply to the kernel: {e}");
+        }
+    }
+
+    async fn dispatch(&self, ino: u64, op: Op) -> Result<Vec<u8>, Errno> {
+        match op {
+            Op::Lookup { name } => self.lookup(ino, name).await,
+            Op::Getattr => {
+                let stat = self.stat(ino).await?;
+                Ok(fuse::attr_out(&self.attr(ino, &stat), TTL))
+            },
+            Op::Setattr { valid, size, mtime, mode, uid, gid } => {
+                // 9P owners are names, which we can't make up from ids
+                if valid & fuse::FATTR_UID != 0 && uid != self.uid || valid & fuse::FATTR_GID != 0 && gid != self.gid {
+                    return Err(Errno::EPERM);
+                }
+                let mut changes = StatChanges::new();
+                if valid & fuse::FATTR_MODE != 0 {
+                    let stat = self.stat(ino).await?;
+                    changes.mode((stat.mode & !0o777) | (mode & 0o777));
+                }
+                if valid & fuse::FATTR_SIZE != 0 {
+                    changes.length(size);
+                }
+                if valid & fuse::FATTR_MTIME_NOW != 0 {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_err(|_| Errno::EINVAL)?;
+                    changes.mtime(u32::try_from(now.as_secs()).map_err(|_| Errno::EINVAL)?);
+                } else if valid & fuse::FATTR_MTIME != 0 {
+                    changes.mtime(u32::try_from(mtime).map_err(|_| Errno::EINVAL)?);
+                }
+                // Nothing else can be changed over 9P, and changing nothing would mean a sync
+                if valid & (fuse::FATTR_MODE | fuse::FATTR_SIZE | fuse::FATTR_MTIME) != 0 {
+                    self.wstat(ino, &changes).await?;
+                }
+                let stat = self.stat(ino).await?;
+                Ok(fuse::attr_out(&self.attr(ino, &stat), TTL))
+            },
+            Op::Mknod { name, mode } => {
+                // 9P has no devices, fifos or sockets
+                if mode & fuse::S_IFMT != fuse::S_IFREG {
+                    return Err(Errno::EPERM);
+                }
+                let name = name.into_string().map_err(|_| Errno::EINVAL)?;
+                let parent = self.node(ino, |node| node.dir.clone())?.ok_or(Errno::ENOTDIR)?;
+                let file = parent.create_file(&name, mode & 0o777, OpenOptions::new().read(true)).await.map_err(errno)?;
+                let stat = file.stat().await.map_err(errno)?;
+                Ok(self.entry(parent, name, &stat, None))
+            },
+            Op::Mkdir { name, mode } => {
+                let name = name.into_string().map_err(|_| Errno::EINVAL)?;
+                let parent = self.node(ino, |node| node.dir.clone())?.ok_or(Errno::ENOTDIR)?;
+                let dir = parent.create_dir(&name, mode & 0o777).await.map_err(errno)?;
+                let stat = dir.stat().await.map_err(errno)?;
+                Ok(self.entry(parent, name, &stat, Some(Arc::new(dir))))
+            },
+            Op::Unlink { name } => self.remove(ino, name, false).await,
+            Op::Rmdir { name } => self.remove(ino, name, true).await,
+            Op::Create { name, mode, flags } => {
+                let name = name.into_string().map_err(|_| Errno::EINVAL)?;
+                let parent = self.node(ino, |node| node.dir.clone())?.ok_or(Errno::ENOTDIR)?;
+                let file = parent.create_file(&name, mode & 0o777, &open_options(flags)).await.map_err(errno)?;
+                let stat = file.stat().await.map_err(errno)?;
+                let mut out = self.entry(parent, name, &stat, None);
+                out.extend_from_slice(&fuse::open_out(self.insert_handle(Handle::File(Arc::new(file)))));
+                Ok(out)
+            },
+            Op::Rename { name, newdir, newname } => {
+                let name = name.into_string().map_err(|_| Errno::ENOENT)?;
+                let newname = newname.into_string().map_err(|_| Errno::EINVAL)?;
+                // 9P only renames within a directory, so moving elsewhere takes a copy
+                if newdir != ino {
+                    return Err(Errno::EXDEV);
+                }
+                let parent = self.node(ino, |node| node.dir.clone())?.ok_or(Errno::ENOTDIR)?;
+                parent.wstat_at(&name, StatChanges::new().name(&newname)).await.map_err(errno)?;
+
+                // Fids follow the file, only the name it's looked up by again changes
+                let mut nodes = self.nodes.lock().unwrap();
+                for node in nodes.by_ino.values_mut() {
+                    if let Some((dir, node_name)) = &mut node.parent && Arc::ptr_eq(dir, &parent) && *node_name == name {
+                        node_name.clone_from(&newname);
+                    }
+                }
+                Ok(Vec::new())
+            },
+            Op::Open { flags } => {
+                let (parent, name) = self.node(ino, |node| node.parent.clone())?.ok_or(Errno::EISDIR)?;
+                let file = parent.open_with(&name, &open_options(flags)).await.map_err(errno)?;
+                Ok(fuse::open_out(self.insert_handle(Handle::File(Arc::new(file)))))
+            },
+            Op::Read { fh, offset, size } => {
+                let file = self.file(fh)?;
+                let mut data = Vec::with_capacity(size as usize);
+                // All at once, a reply's worth each, until one comes up short
+                let count = file.read_size().max(1);
+                let mut reads = (0..size).step_by(count as usize).map(|at| {
+                    let (file, count) = (&file, count.min(size - at));
+                    async move { (count, file.read_at(count, offset + u64::from(at)).await) }
+                }).collect::<FuturesOrdered<_>>();
+                while let Some((count, chunk)) = reads.next().await {
+                    let chunk = chunk.map_err(errno)?;
+                    data.extend_from_slice(&chunk);
+                    if chunk.len() < count as usize {
+                        break;
+                    }
+                }
+                // Dropping the rest flushes them. Only an empty reply means the end of the
+                // file, and the kernel takes a short read for that.
+                drop(reads);
+                while data.len() < size as usize {
+                    let chunk = file.read_at(size - data.len() as u32, offset + data.len() as u64).await.map_err(errno)?;
+                    if chunk.is_empty() {
+                        break;
+                    }
+                    data.extend_from_slice(&chunk);
+                }
+                Ok(data)
+            },
+            Op::Write { fh, offset, data } => {
+                let file = self.file(fh)?;
+                write_all(&file, data.clone(), offset).await.map_err(errno)?;
+                Ok(fuse::write_out(data.len() as u32))
+            },
+            Op::Flush => Ok(Vec::new()),
+            Op::Release { fh } | Op::Releasedir { fh } => {
+                // Dropping the last reference clunks the fid
+                self.handles.lock().unwrap().remove(&fh);
+                Ok(Vec::new())
+            },
+            Op::Opendir => {
+                let dir = self.node(ino, |node| node.dir.clone())?.ok_or(Errno::ENOTDIR)?;
+                let mut read_dir = dir.try_clone().await.map_err(errno)?.read_dir().await.map_err(errno)?;
+                let mut stats = Vec::new();
+                while let Some(stat) = read_dir.next_entry().await.map_err(errno)? {
+                    stats.push(stat);
+                }
+                let nodes = self.nodes.lock().unwrap();
+                let entries = stats.into_iter().map(|stat| DirEntry {
+                    ino: nodes.by_qid.get(&stat.qid.path).copied().unwrap_or(UNKNOWN_INO),
+                    kind: if stat.mode & DMDIR != 0 { fuse::DT_DIR } else { fuse::DT_REG },
+                    name: stat.name.to_string()
+                }).collect();
+                drop(nodes);
+                Ok(fuse::open_out(self.insert_handle(Handle::Dir(Arc::new(entries)))))
+            },
+            Op::Readdir { fh, offset, size } => {
+                let entries = self.dir_entries(fh)?;
+                let mut buf = DirBuf::new(size);
+                for (i, entry) in entries.iter().enumerate().skip(offset as usize) {
+                    if !buf.push(entry.ino, i as u64 + 1, entry.kind, &entry.name) {
+                        break;
+                    }
+                }
+                Ok(buf.into_inner())
+            },
+            Op::Statfs => Ok(fuse::statfs_out(MAX_NAME_LEN)),
+            Op::Other(opcode) => {
+                debug!("unsupported FUSE opcode {opcode}");
+                Err(Errno::ENOSYS)
+            },
+            Op::Forget { .. } | Op::BatchForget { .. } | Op::Interrupt => unreachable!()
+        }
+    }
+
+    fn node<T>(&self, ino: u64, f: impl FnOnce(&Node) -> T) -> Result<T, Errno> {
+        self.nodes.lock().unwrap().by_ino.get(&ino).map(f).ok_or(Errno::ESTALE)
+    }
+
+    fn file(&self, fh: u64) -> Result<Arc<File>, Errno> {
+        match self.handles.lock().unwrap().get(&fh) {
+            Some(Handle::File(file)) => Ok(file.clone()),
+            _ => Err(Errno::EBADF)
+        }
+    }
+
+    fn dir_entries(&self, fh: u64) -> Result<Arc<Vec<DirEntry>>, Errno> {
+        match self.handles.lock().unwrap().get(&fh) {
+            Some(Handle::Dir(entries)) => Ok(entries.clone()),
+            _ => Err(Errno::EBADF)
+        }
+    }
+
+    fn insert_handle(&self, handle: Handle) -> u64 {
+        let fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
+        self.handles.lock().unwrap().insert(fh, handle);
+        fh
+    }
+
+    fn attr(&self, ino: u64, stat: &Stat) -> Attr {
+        let dir = stat.mode & DMDIR != 0;
+        Attr {
+            ino,
+            size: stat.length,
+            blocks: stat.length.div_ceil(512),
+            atime: stat.atime.into(),
+            mtime: stat.mtime.into(),
+            ctime: stat.mtime.into(),
+            mode: if dir { fuse::S_IFDIR } else { fuse::S_IFREG } | (stat.mode & 0o777),
+            nlink: if dir { 2 } else { 1 },
+            uid: self.uid,
+            gid: self.gid,
+            blksize: 4096
+        }
+    }
+
+    async fn stat(&self, ino: u64) -> Result<Stat, Errno> {
+        let (dir, parent) = self.node(ino, |node| (node.dir.clone(), node.parent.clone()))?;
+        let res = match (dir, parent) {
+            (Some(dir), _) => dir.stat().await,
+            (None, Some((parent, name))) => parent.stat_at(&name).await,
+            (None, None) => return Err(Errno::ESTALE)
+        };
+        res.map_err(errno)
+    }
+
+    async fn wstat(&self, ino: u64, changes: &StatChanges) -> Result<(), Errno> {
+        let (dir, parent) = self.node(ino, |node| (node.dir.clone(), node.parent.clone()))?;
+        let res = match (dir, parent) {
+            (Some(dir), _) => dir.wstat(changes).await,
+            (None, Some((parent, name))) => parent.wstat_at(&name, changes).await,
+            (None, None) => return Err(Errno::ESTALE)
+        };
+        res.map_err(errno)
+    }
+
+    async fn lookup(&self, parent_ino: u64, name: OsString) -> Result<Vec<u8>, Errno> {
+        // 9P names are UTF-8
+        let name = name.into_string().map_err(|_| Errno::ENOENT)?;
+        let parent = self.node(parent_ino, |node| node.dir.clone())?.ok_or(Errno::ENOTDIR)?;
+        let stat = parent.stat_at(&name).await.map_err(errno)?;
+        let qid_path = stat.qid.path;
+
+        let known = self.nodes.lock().unwrap().by_qid.contains_key(&qid_path);
+        let dir = if stat.mode & DMDIR != 0 && !known {
+            Some(Arc::new(parent.open_dir_at(&name).await.map_err(errno)?))
+        } else {
+            None
+        };
+        Ok(self.entry(parent, name, &stat, dir))
+    }
+
+    /// Counts a lookup of `name` in `parent`, making it a node if it isn't one yet, and
+    /// returns the reply that tells the kernel about it. `dir` is its fid, if it's a
+    /// directory we've walked to already.
+    fn entry(&self, parent: Arc<Directory>, name: String, stat: &Stat, dir: Option<Arc<Directory>>) -> Vec<u8> {
+        let qid_path = stat.qid.path;
+        let mut nodes = self.nodes.lock().unwrap();
+        let ino = match nodes.by_qid.get(&qid_path) {
+            Some(&ino) => ino,
+            None => {
+                let ino = nodes.next_ino;
+                nodes.next_ino += 1;
+                nodes.by_qid.insert(qid_path, ino);
+                nodes.by_ino.insert(ino, Node { parent: Some((parent, name)), dir, qid_path, nlookup: 0 });
+                ino
+            }
+        };
+        nodes.by_ino.get_mut(&ino).unwrap().nlookup += 1;
+        drop(nodes);
+
+        fuse::entry_out(&self.attr(ino, stat), TTL)
+    }
+
+    /// Removes `name` from the directory `parent_ino`, if it's a directory when `dir`
+    /// is set and a file otherwise. Its node stays until the kernel forgets it.
+    async fn remove(&self, parent_ino: u64, name: OsString, dir: bool) -> Result<Vec<u8>, Errno> {
+        let name = name.into_string().map_err(|_| Errno::ENOENT)?;
+        let parent = self.node(parent_ino, |node| node.dir.clone())?.ok_or(Errno::ENOTDIR)?;
+        // 9P removes either, so we check which it is first
+        let stat = parent.stat_at(&name).await.map_err(errno)?;
+        match (stat.mode & DMDIR != 0, dir) {
+            (true, false) => return Err(Errno::EISDIR),
+            (false, true) => return Err(Errno::ENOTDIR),
+            _ => ()
+        }
+        parent.remove_at(&name).await.map_err(errno)?;
+        // Whatever the server makes next may get the same qid path
+        self.nodes.lock().unwrap().by_qid.remove(&stat.qid.path);
+        Ok(Vec::new())
+    }
+
+    fn forget(&self, ino: u64, nlookup: u64) {
+        let mut nodes = self.nodes.lock().unwrap();
+        let Some(node) = nodes.by_ino.get_mut(&ino) else { return };
+        node.nlookup = node.nlookup.saturating_sub(nlookup);
+        // Dropping the node clunks its fid
+        if node.nlookup == 0 && ino != ROOT_ID {
+            let qid_path = node.qid_path;
+            nodes.by_ino.remove(&ino);
+            // Unless it was removed, and the qid path has gone to something else since
+            if nodes.by_qid.get(&qid_path) == Some(&ino) {
+                nodes.by_qid.remove(&qid_path);
+            }
+        }
+    }
+}
+
+fn open_options(flags: u32) -> OpenOptions {
+    let flags = OFlag::from_bits_truncate(flags as i32);
+    let access = flags & OFlag::O_ACCMODE;
+    let mut options = OpenOptions::new();
+    options
+        .read(access != OFlag::O_WRONLY)
+        .write(access != OFlag::O_RDONLY)
+        .truncate(flags.contains(OFlag::O_TRUNC) && access != OFlag::O_RDONLY);
+    options
+}
+
+async fn write_all(file: &File, mut data: Bytes, mut offset: u64) -> io::Result<()> {
+    while !data.is_empty() {
+        let n = file.write_at(data.clone(), offset).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        let _ = data.split_to(n as usize);
+        offset += u64::from(n);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File as DevFile, os::{fd::OwnedFd, unix::net::UnixDatagram}};
+
+    use async_trait::async_trait;
+    use bytes::BytesMut;
+    use client::{Filesystem, Transport};
+    use npwire::*;
+    use tokio::sync::{mpsc, Mutex as AsyncMutex};
+
+    use super::*;
+
+    const ROOT_QID: Qid = Qid { type_: QTDIR, version: 0, path: 0 };
+
+    /// A 9P server with one directory, which holds everything made in it.
+    struct FakeServer {
+        tree: Mutex<Tree>,
+        replies_tx: mpsc::UnboundedSender<Bytes>,
+        replies: AsyncMutex<mpsc::UnboundedReceiver<Bytes>>
+    }
+
+    #[derive(Default)]
+    struct Tree {
+        /// Names to their qids and permissions
+        files: HashMap<String, (Qid, u32)>,
+        /// What fids are on, `None` being the root
+        fids: HashMap<u32, Option<String>>
+    }
+
+    impl FakeServer {
+        fn new() -> Self {
+            let (replies_tx, replies) = mpsc::unbounded_channel();
+            Self { tree: Mutex::default(), replies_tx, replies: AsyncMutex::new(replies) }
+        }
+
+        fn answer(&self, req: TMessage) -> RMessage {
+            let not_found = || RMessage::Rerror(Errno::ENOENT.desc().into());
+            let mut tree = self.tree.lock().unwrap();
+            let tree = &mut *tree;
+            match req {
+                TMessage::Tversion(Tversion { msize, version }) => RMessage::Rversion(Rversion { msize, version }),
+                TMessage::Tattach(Tattach { fid, .. }) => {
+                    tree.fids.insert(fid, None);
+                    RMessage::Rattach(Rattach { qid: ROOT_QID })
+                },
+                TMessage::Twalk(Twalk { fid, newfid, wname }) => {
+                    let Some(from) = tree.fids.get(&fid).cloned() else { return not_found() };
+                    match (from, wname.as_slice()) {
+                        (from, []) => {
+                            tree.fids.insert(newfid, from);
+                            RMessage::Rwalk(Rwalk { wqid: Vec::new() })
+                        },
+                        (None, [name]) => {
+                            let Some(&(qid, _)) = tree.files.get(&**name) else { return not_found() };
+                            tree.fids.insert(newfid, Some(name.to_string()));
+                            RMessage::Rwalk(Rwalk { wqid: vec![qid] })
+                        },
+                        _ => not_found()
+                    }
+                },
+                TMessage::Tstat(Tstat { fid }) => {
+                    let (name, qid, mode) = match tree.fids.get(&fid) {
+                        Some(None) => ("/".to_owned(), ROOT_QID, DMDIR | 0o755),
+                        Some(Some(name)) => match tree.files.get(name) {
+                            Some(&(qid, mode)) => (name.clone(), qid, mode),
+                            None => return not_found()
+                        },
+                        None => return not_found()
+                    };
+                    RMessage::Rstat(Rstat { stat: Stat {
+                        type_: 0, dev: 0, qid, mode, atime: 0, mtime: 0, length: 0,
+                        name: name.into(), uid: "me".into(), gid: "me".into(), muid: "me".into()
+                    } })
+                },
+                TMessage::Tcreate(Tcreate { fid, name, perm, .. }) => {
+                    if tree.files.contains_key(&*name) {
+                        return RMessage::Rerror(Errno::EEXIST.desc().into());
+                    }
+                    let type_ = if perm & DMDIR != 0 { QTDIR } else { QTFILE };
+                    let qid = Qid { type_, version: 0, path: tree.files.len() as u64 + 1 };
+                    tree.files.insert(name.to_string(), (qid, perm));
+                    tree.fids.insert(fid, Some(name.to_string()));
+                    RMessage::Rcreate(Rcreate { qid, iounit: 0 })
+                },
+                TMessage::Topen(Topen { fid, .. }) => {
+                    let qid = match tree.fids.get(&fid) {
+                        Some(Some(name)) => tree.files[name].0,
+                        _ => ROOT_QID
+                    };
+                    RMessage::Ropen(Ropen { qid, iounit: 0 })
+                },
+                TMessage::Tclunk(Tclunk { fid }) => {
+                    tree.fids.remove(&fid);
+                    RMessage::Rclunk(Rclunk)
+                },
+                TMessage::Tremove(Tremove { fid }) => match tree.fids.remove(&fid) {
+                    Some(Some(name)) => {
+                        tree.files.remove(&name);
+                        RMessage::Rremove(Rremove)
+                    },
+                    _ => RMessage::Rerror(Errno::EPERM.desc().into())
+                },
+                _ => RMessage::Rerror(Errno::ENOSYS.desc().into())
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Transport for FakeServer {
+        async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+            let reply = self.replies.lock().await.recv().await.ok_or(io::ErrorKind::BrokenPipe)?;
+            buf[..reply.len()].copy_from_slice(&reply);
+            Ok(reply.len())
+        }
+
+        async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+            let (tag, req) = deserialize_t(Bytes::copy_from_slice(buf)).map_err(io::Error::other)?;
+            let reply = self.answer(req).serialize(tag).map_err(io::Error::other)?;
+            let _ = self.replies_tx.send(reply);
+            Ok(buf.len())
+        }
+    }
+
+    /// Plays the kernel's part, over a socket in place of /dev/fuse.
+    struct Kernel {
+        dev: UnixDatagram,
+        session: Session,
+        fs: NineFs,
+        unique: u64
+    }
+
+    impl Kernel {
+        async fn new() -> Self {
+            let fsys = Filesystem::new(FakeServer::new()).await.unwrap();
+            let root = fsys.attach("me", "").await.unwrap();
+            let (dev, theirs) = UnixDatagram::pair().unwrap();
+            Self {
+                dev,
+                session: Session::from_dev(DevFile::from(OwnedFd::from(theirs))),
+                fs: NineFs::new(root, 1000, 1000).await.unwrap(),
+                unique: 0
+            }
+        }
+
+        /// Sends a request and returns the error in the reply, as a negative errno, and
+        /// its body.
+        async fn call(&mut self, opcode: u32, nodeid: u64, args: &[u8], name: &str) -> (i32, Vec<u8>) {
+            self.unique += 1;
+            let mut msg = BytesMut::new();
+            let len = 40 + args.len() + name.len() + 1;
+            msg.extend_from_slice(&(len as u32).to_ne_bytes());
+            msg.extend_from_slice(&opcode.to_ne_bytes());
+            msg.extend_from_slice(&self.unique.to_ne_bytes());
+            msg.extend_from_slice(&nodeid.to_ne_bytes());
+            // uid, gid, pid, padding
+            msg.extend_from_slice(&[0; 16]);
+            msg.extend_from_slice(args);
+            msg.extend_from_slice(name.as_bytes());
+            msg.extend_from_slice(&[0]);
+            self.dev.send(&msg).unwrap();
+
+            let mut buf = Vec::new();
+            let req = self.session.receive(&mut buf).unwrap().unwrap();
+            assert_eq!(req.unique, self.unique);
+            self.fs.handle(&self.session, req).await;
+
+            let mut reply = vec![0; 4096];
+            let n = self.dev.recv(&mut reply).unwrap();
+            reply.truncate(n);
+            assert_eq!(u32::from_ne_bytes(reply[..4].try_into().unwrap()) as usize, n);
+            assert_eq!(u64::from_ne_bytes(reply[8..16].try_into().unwrap()), self.unique);
+            (i32::from_ne_bytes(reply[4..8].try_into().unwrap()), reply.split_off(16))
+        }
+    }
+
+    fn args(fields: &[u32]) -> Vec<u8> {
+        fields.iter().flat_map(|v| v.to_ne_bytes()).collect()
+    }
+
+    /// The node and mode out of an entry reply
+    fn entry(body: &[u8]) -> (u64, u32) {
+        (u64::from_ne_bytes(body[..8].try_into().unwrap()), u32::from_ne_bytes(body[100..104].try_into().unwrap()))
+    }
+
+    const MKNOD: u32 = 8;
+    const MKDIR: u32 = 9;
+    const UNLINK: u32 = 10;
+    const RMDIR: u32 = 11;
+    const LOOKUP: u32 = 1;
+    const CREATE: u32 = 35;
+
+    #[tokio::test]
+    async fn creates_and_removes() {
+        let mut kernel = Kernel::new().await;
+        let errno = |e: Errno| -(e as i32);
+
+        // mode, umask
+        let (error, body) = kernel.call(MKDIR, ROOT_ID, &args(&[0o755, 0o022]), "sub").await;
+        assert_eq!(error, 0);
+        let (sub, mode) = entry(&body);
+        assert_eq!(mode, fuse::S_IFDIR | 0o755);
+
+        // flags, mode, umask, open_flags
+        let (error, body) = kernel.call(CREATE, ROOT_ID, &args(&[OFlag::O_RDWR.bits() as u32, fuse::S_IFREG | 0o644, 0o022, 0]), "f").await;
+        assert_eq!(error, 0);
+        let (file, mode) = entry(&body);
+        assert_eq!(mode, fuse::S_IFREG | 0o644);
+        assert_ne!(file, sub);
+        // The entry, then the open file
+        assert_eq!(body.len(), fuse::entry_out(&Attr::default(), TTL).len() + 16);
+
+        // mode, rdev, umask, padding
+        let (error, _) = kernel.call(MKNOD, ROOT_ID, &args(&[0o010644, 0, 0o022, 0]), "fifo").await;
+        assert_eq!(error, errno(Errno::EPERM));
+        let (error, body) = kernel.call(MKNOD, ROOT_ID, &args(&[fuse::S_IFREG | 0o600, 0, 0o022, 0]), "g").await;
+        assert_eq!(error, 0);
+        assert_eq!(entry(&body).1, fuse::S_IFREG | 0o600);
+        let (error, _) = kernel.call(MKDIR, ROOT_ID, &args(&[0o755, 0o022]), "g").await;
+        assert_eq!(error, errno(Errno::EEXIST));
+
+        // Each only removes its own kind
+        assert_eq!(kernel.call(UNLINK, ROOT_ID, &[], "sub").await.0, errno(Errno::EISDIR));
+        assert_eq!(kernel.call(RMDIR, ROOT_ID, &[], "f").await.0, errno(Errno::ENOTDIR));
+
+        assert_eq!(kernel.call(UNLINK, ROOT_ID, &[], "f").await, (0, Vec::new()));
+        assert_eq!(kernel.call(RMDIR, ROOT_ID, &[], "sub").await, (0, Vec::new()));
+        assert_eq!(kernel.call(LOOKUP, ROOT_ID, &[], "f").await.0, errno(Errno::ENOENT));
+        assert_eq!(kernel.call(LOOKUP, ROOT_ID, &[], "sub").await.0, errno(Errno::ENOENT));
+        assert_eq!(kernel.call(UNLINK, ROOT_ID, &[], "f").await.0, errno(Errno::ENOENT));
+    }
+}
@@ -0,0 +1,456 @@
+//! Just enough of the FUSE kernel protocol (see linux/fuse.h) to serve a filesystem from
+//! /dev/fuse without libfuse. Mounting this way needs root, or CAP_SYS_ADMIN.
+
+use std::{ffi::OsString, fs::{File, OpenOptions}, io::{self, Read as _, Write as _}, os::{fd::AsRawFd, unix::ffi::OsStringExt}, path::{Path, PathBuf}, time::Duration};
+
+use bytes::Bytes;
+use nix::{errno::Errno, mount::{mount, umount2, MntFlags, MsFlags}, unistd::{getgid, getuid}};
+use tracing::debug;
+
+pub const ROOT_ID: u64 = 1;
+
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const DT_DIR: u32 = 4;
+pub const DT_REG: u32 = 8;
+
+// We don't need anything newer
+const KERNEL_VERSION: u32 = 7;
+const KERNEL_MINOR_VERSION: u32 = 31;
+
+pub const MAX_WRITE: u32 = 128 * 1024;
+// The largest write, plus its headers
+const BUFFER_SIZE: usize = MAX_WRITE as usize + 4096;
+const MAX_BACKGROUND: u16 = 16;
+
+const LOOKUP: u32 = 1;
+const FORGET: u32 = 2;
+const GETATTR: u32 = 3;
+const SETATTR: u32 = 4;
+const MKNOD: u32 = 8;
+const MKDIR: u32 = 9;
+const UNLINK: u32 = 10;
+const RMDIR: u32 = 11;
+const RENAME: u32 = 12;
+const OPEN: u32 = 14;
+const READ: u32 = 15;
+const WRITE: u32 = 16;
+const STATFS: u32 = 17;
+const RELEASE: u32 = 18;
+const FLUSH: u32 = 25;
+const INIT: u32 = 26;
+const OPENDIR: u32 = 27;
+const READDIR: u32 = 28;
+const RELEASEDIR: u32 = 29;
+const CREATE: u32 = 35;
+const INTERRUPT: u32 = 36;
+const DESTROY: u32 = 38;
+const BATCH_FORGET: u32 = 42;
+
+const ASYNC_READ: u32 = 1 << 0;
+const BIG_WRITES: u32 = 1 << 5;
+const PARALLEL_DIROPS: u32 = 1 << 18;
+
+// Which fields of a SETATTR to set
+pub const FATTR_MODE: u32 = 1 << 0;
+pub const FATTR_UID: u32 = 1 << 1;
+pub const FATTR_GID: u32 = 1 << 2;
+pub const FATTR_SIZE: u32 = 1 << 3;
+pub const FATTR_MTIME: u32 = 1 << 5;
+pub const FATTR_MTIME_NOW: u32 = 1 << 8;
+
+const IN_HEADER_LEN: usize = 40;
+const OUT_HEADER_LEN: usize = 16;
+
+/// What the kernel asks for. Requests the filesystem needn't care about, like INIT,
+/// are handled by [`Session`].
+#[derive(Debug)]
+pub enum Op {
+    Lookup { name: OsString },
+    /// Needs no reply
+    Forget { nlookup: u64 },
+    /// Needs no reply
+    BatchForget { nodes: Vec<(u64, u64)> },
+    Getattr,
+    /// Only the fields flagged in `valid` are meant
+    Setattr { valid: u32, size: u64, mtime: u64, mode: u32, uid: u32, gid: u32 },
+    /// `mode` includes the file type
+    Mknod { name: OsString, mode: u32 },
+    Mkdir { name: OsString, mode: u32 },
+    Unlink { name: OsString },
+    Rmdir { name: OsString },
+    Rename { name: OsString, newdir: u64, newname: OsString },
+    /// Creates and opens at once, answered with both an entry and an open file
+    Create { name: OsString, mode: u32, flags: u32 },
+    Open { flags: u32 },
+    Read { fh: u64, offset: u64, size: u32 },
+    Write { fh: u64, offset: u64, data: Bytes },
+    Flush,
+    Release { fh: u64 },
+    Opendir,
+    Readdir { fh: u64, offset: u64, size: u32 },
+    Releasedir { fh: u64 },
+    Statfs,
+    /// Needs no reply, since we don't act on it
+    Interrupt,
+    /// Anything else, to be answered with ENOSYS
+    Other(u32)
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub unique: u64,
+    pub nodeid: u64,
+    pub op: Op
+}
+
+/// Reads native-endian fields off the front of a request.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "short FUSE request"));
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_ne_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_ne_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// A NUL-terminated name
+    fn name(&mut self) -> io::Result<OsString> {
+        let len = self.0.iter().position(|&b| b == 0)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unterminated name"))?;
+        let name = self.take(len + 1)?;
+        Ok(OsString::from_vec(name[..len].to_vec()))
+    }
+}
+
+fn put32(out: &mut Vec<u8>, v: u32) {
+    out.extend_from_slice(&v.to_ne_bytes());
+}
+
+fn put64(out: &mut Vec<u8>, v: u64) {
+    out.extend_from_slice(&v.to_ne_bytes());
+}
+
+/// A mounted filesystem's connection to the kernel. Dropping it unmounts.
+#[derive(Debug)]
+pub struct Session {
+    dev: File,
+    /// `None` when there's no real kernel on the other end, only a test
+    mountpoint: Option<PathBuf>
+}
+
+impl Session {
+    pub fn mount(mountpoint: &Path) -> io::Result<Self> {
+        let dev = OpenOptions::new().read(true).write(true).open("/dev/fuse")?;
+        let options = format!("fd={},rootmode={:o},user_id={},group_id={}", dev.as_raw_fd(), S_IFDIR, getuid(), getgid());
+        mount(Some("ninewire"), mountpoint, Some("fuse.ninewire"), MsFlags::MS_NOSUID | MsFlags::MS_NODEV, Some(options.as_str()))?;
+        Ok(Self { dev, mountpoint: Some(mountpoint.to_owned()) })
+    }
+
+    /// A session that talks to whatever is on the other end of `dev`, which must keep
+    /// requests apart the way /dev/fuse does, like a datagram socket.
+    #[cfg(test)]
+    pub fn from_dev(dev: File) -> Self {
+        Self { dev, mountpoint: None }
+    }
+
+    pub fn unmount(&self) -> io::Result<()> {
+        if let Some(mountpoint) = &self.mountpoint {
+            umount2(mountpoint, MntFlags::MNT_DETACH)?;
+        }
+        Ok(())
+    }
+
+    /// Blocks until the next request, reading it into `buf`. Returns `None` once
+    /// unmounted.
+    pub fn receive(&self, buf: &mut Vec<u8>) -> io::Result<Option<Request>> {
+        buf.resize(BUFFER_SIZE, 0);
+        loop {
+            let n = match (&self.dev).read(buf) {
+                Ok(n) => n,
+                // The request was interrupted before we got to it
+                Err(e) if e.raw_os_error() == Some(Errno::ENOENT as i32) => continue,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.raw_os_error() == Some(Errno::ENODEV as i32) => return Ok(None),
+                Err(e) => return Err(e)
+            };
+
+            let mut r = Reader(&buf[..n]);
+            let _len = r.u32()?;
+            let opcode = r.u32()?;
+            let unique = r.u64()?;
+            let nodeid = r.u64()?;
+            r.take(IN_HEADER_LEN - 24)?;
+
+            let op = match opcode {
+                INIT => {
+                    let (major, minor) = (r.u32()?, r.u32()?);
+                    let max_readahead = r.u32()?;
+                    let flags = r.u32()?;
+                    debug!("FUSE {major}.{minor}, flags {flags:#x}");
+                    if major < KERNEL_VERSION {
+                        self.reply(unique, Err(Errno::EPROTO))?;
+                        return Err(io::Error::new(io::ErrorKind::Unsupported, "FUSE too old"));
+                    }
+                    self.reply(unique, Ok(&init_out(max_readahead, flags)))?;
+                    continue;
+                },
+                DESTROY => {
+                    self.reply(unique, Ok(&[]))?;
+                    return Ok(None);
+                },
+                LOOKUP => Op::Lookup { name: r.name()? },
+                FORGET => Op::Forget { nlookup: r.u64()? },
+                BATCH_FORGET => {
+                    let count = r.u32()?;
+                    r.u32()?;
+                    let nodes = (0..count).map(|_| Ok((r.u64()?, r.u64()?))).collect::<io::Result<_>>()?;
+                    Op::BatchForget { nodes }
+                },
+                GETATTR => Op::Getattr,
+                SETATTR => {
+                    let valid = r.u32()?;
+                    // padding, fh
+                    r.take(12)?;
+                    let size = r.u64()?;
+                    // lock_owner, atime
+                    r.take(16)?;
+                    let mtime = r.u64()?;
+                    // ctime, and the nanoseconds of all three
+                    r.take(20)?;
+                    let mode = r.u32()?;
+                    r.u32()?;
+                    let (uid, gid) = (r.u32()?, r.u32()?);
+                    Op::Setattr { valid, size, mtime, mode, uid, gid }
+                },
+                MKNOD => {
+                    let mode = r.u32()?;
+                    // rdev, umask, padding
+                    r.take(12)?;
+                    Op::Mknod { name: r.name()?, mode }
+                },
+                MKDIR => {
+                    let mode = r.u32()?;
+                    // umask, which the kernel applies itself
+                    r.u32()?;
+                    Op::Mkdir { name: r.name()?, mode }
+                },
+                UNLINK => Op::Unlink { name: r.name()? },
+                RMDIR => Op::Rmdir { name: r.name()? },
+                RENAME => {
+                    let newdir = r.u64()?;
+                    Op::Rename { name: r.name()?, newdir, newname: r.name()? }
+                },
+                CREATE => {
+                    let (flags, mode) = (r.u32()?, r.u32()?);
+                    // umask, open_flags
+                    r.take(8)?;
+                    Op::Create { name: r.name()?, mode, flags }
+                },
+                OPEN => Op::Open { flags: r.u32()? },
+                READ => {
+                    let (fh, offset, size) = (r.u64()?, r.u64()?, r.u32()?);
+                    Op::Read { fh, offset, size }
+                },
+                WRITE => {
+                    let (fh, offset, size) = (r.u64()?, r.u64()?, r.u32()?);
+                    // write_flags, lock_owner, flags, padding
+                    r.take(20)?;
+                    Op::Write { fh, offset, data: Bytes::copy_from_slice(r.take(size as usize)?) }
+                },
+                FLUSH => Op::Flush,
+                RELEASE => Op::Release { fh: r.u64()? },
+                OPENDIR => Op::Opendir,
+                READDIR => {
+                    let (fh, offset, size) = (r.u64()?, r.u64()?, r.u32()?);
+                    Op::Readdir { fh, offset, size }
+                },
+                RELEASEDIR => Op::Releasedir { fh: r.u64()? },
+                STATFS => Op::Statfs,
+                INTERRUPT => Op::Interrupt,
+                opcode => Op::Other(opcode)
+            };
+            return Ok(Some(Request { unique, nodeid, op }));
+        }
+    }
+
+    /// Answers request `unique` with either a reply body or an error.
+    pub fn reply(&self, unique: u64, res: Result<&[u8], Errno>) -> io::Result<()> {
+        let (error, data) = match res {
+            Ok(data) => (0, data),
+            Err(errno) => (-(errno as i32), &[][..])
+        };
+        let mut msg = Vec::with_capacity(OUT_HEADER_LEN + data.len());
+        put32(&mut msg, (OUT_HEADER_LEN + data.len()) as u32);
+        put32(&mut msg, error as u32);
+        put64(&mut msg, unique);
+        msg.extend_from_slice(data);
+        match (&self.dev).write(&msg) {
+            // The request was interrupted, and nobody's waiting anymore
+            Err(e) if e.raw_os_error() == Some(Errno::ENOENT as i32) => Ok(()),
+            res => res.map(drop)
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // Fails harmlessly if someone else unmounted us already
+        let _ = self.unmount();
+    }
+}
+
+fn init_out(max_readahead: u32, flags: u32) -> Vec<u8> {
+    let mut out = Vec::with_capacity(64);
+    put32(&mut out, KERNEL_VERSION);
+    put32(&mut out, KERNEL_MINOR_VERSION);
+    put32(&mut out, max_readahead);
+    put32(&mut out, flags & (ASYNC_READ | BIG_WRITES | PARALLEL_DIROPS));
+    out.extend_from_slice(&MAX_BACKGROUND.to_ne_bytes());
+    // congestion_threshold
+    out.extend_from_slice(&(MAX_BACKGROUND * 3 / 4).to_ne_bytes());
+    put32(&mut out, MAX_WRITE);
+    // time_gran: 9P times are in seconds
+    put32(&mut out, 1_000_000_000);
+    out.resize(64, 0);
+    out
+}
+
+/// A file's attributes, as the kernel wants them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Attr {
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub blksize: u32
+}
+
+impl Attr {
+    fn put(&self, out: &mut Vec<u8>) {
+        for v in [self.ino, self.size, self.blocks, self.atime, self.mtime, self.ctime] {
+            put64(out, v);
+        }
+        // Nanoseconds of the times
+        for _ in 0..3 {
+            put32(out, 0);
+        }
+        for v in [self.mode, self.nlink, self.uid, self.gid] {
+            put32(out, v);
+        }
+        // rdev
+        put32(out, 0);
+        put32(out, self.blksize);
+        // flags
+        put32(out, 0);
+    }
+}
+
+fn put_ttl(out: &mut Vec<u8>, ttl: Duration) {
+    put64(out, ttl.as_secs());
+}
+
+/// The reply to a lookup: the node is `attr.ino`, and both it and its attributes may
+/// be cached for `ttl`.
+pub fn entry_out(attr: &Attr, ttl: Duration) -> Vec<u8> {
+    let mut out = Vec::with_capacity(128);
+    put64(&mut out, attr.ino);
+    // generation
+    put64(&mut out, 0);
+    put_ttl(&mut out, ttl);
+    put_ttl(&mut out, ttl);
+    put32(&mut out, ttl.subsec_nanos());
+    put32(&mut out, ttl.subsec_nanos());
+    attr.put(&mut out);
+    out
+}
+
+pub fn attr_out(attr: &Attr, ttl: Duration) -> Vec<u8> {
+    let mut out = Vec::with_capacity(104);
+    put_ttl(&mut out, ttl);
+    put32(&mut out, ttl.subsec_nanos());
+    put32(&mut out, 0);
+    attr.put(&mut out);
+    out
+}
+
+pub fn open_out(fh: u64) -> Vec<u8> {
+    let mut out = Vec::with_capacity(16);
+    put64(&mut out, fh);
+    // open_flags, padding
+    put64(&mut out, 0);
+    out
+}
+
+pub fn write_out(size: u32) -> Vec<u8> {
+    let mut out = Vec::with_capacity(8);
+    put32(&mut out, size);
+    put32(&mut out, 0);
+    out
+}
+
+/// We can't know how full the server is, so we say it's empty.
+pub fn statfs_out(namelen: u32) -> Vec<u8> {
+    let mut out = Vec::with_capacity(80);
+    // blocks, bfree, bavail, files, ffree
+    for _ in 0..5 {
+        put64(&mut out, 0);
+    }
+    // bsize
+    put32(&mut out, 4096);
+    put32(&mut out, namelen);
+    // frsize
+    put32(&mut out, 4096);
+    out.resize(80, 0);
+    out
+}
+
+/// The reply to a readdir, up to the size the kernel asked for.
+#[derive(Debug)]
+pub struct DirBuf {
+    buf: Vec<u8>,
+    size: usize
+}
+
+impl DirBuf {
+    pub fn new(size: u32) -> Self {
+        Self { buf: Vec::new(), size: size as usize }
+    }
+
+    /// Adds an entry, unless it doesn't fit. `offset` is where to resume after it.
+    pub fn push(&mut self, ino: u64, offset: u64, kind: u32, name: &str) -> bool {
+        let len = (24 + name.len()).next_multiple_of(8);
+        if self.buf.len() + len > self.size {
+            return false;
+        }
+        put64(&mut self.buf, ino);
+        put64(&mut self.buf, offset);
+        put32(&mut self.buf, name.len() as u32);
+        put32(&mut self.buf, kind);
+        self.buf.extend_from_slice(name.as_bytes());
+        self.buf.resize(self.buf.len().next_multiple_of(8), 0);
+        true
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+}
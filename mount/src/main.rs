@@ -0,0 +1,131 @@
+#![forbid(unsafe_code)]
+
+//! Mounts a ninewire server as a local filesystem, over FUSE.
+
+#[cfg(target_os = "linux")]
+mod fs;
+#[cfg(target_os = "linux")]
+mod fuse;
+
+use std::path::PathBuf;
+
+use clap::Parser;
+
+#[derive(Debug, Parser)]
+#[command(about = "Mounts a ninewire server over FUSE")]
+struct Args {
+    /// Directory to mount on
+    mountpoint: PathBuf,
+    /// The server's name, to look up through the mediator (see MEDIATOR_URL)
+    #[arg(long, default_value = "bugerking")]
+    name: String,
+    /// Connect to host:port directly instead, skipping the mediator
+    #[arg(long)]
+    connect: Option<String>,
+    /// The server's public key, as 64 hex digits
+    #[arg(long, env = "SERVER_KEY")]
+    key: String,
+    #[arg(long, default_value = "anonymous")]
+    uname: String,
+    #[arg(long, default_value = "")]
+    aname: String,
+    /// If the connection drops, connect again and carry on instead of unmounting
+    #[arg(long)]
+    reconnect: bool,
+    /// How many requests to have out to the server at once
+    #[arg(long, default_value_t = client::DEFAULT_MAX_IN_FLIGHT)]
+    max_in_flight: usize
+}
+
+#[cfg(target_os = "linux")]
+async fn connect(addr: Option<&str>, name: &str, key: &[u8; 32]) -> std::io::Result<client::SecureTransport> {
+    match addr {
+        Some(addr) => rendezvous::connect_direct(addr, key).await,
+        None => rendezvous::Mediator::from_env().map_err(std::io::Error::other)?
+            .connect_by_name_pinned(name, key).await.map_err(std::io::Error::other)
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    use std::sync::Arc;
+
+    use anyhow::{anyhow, Context};
+    use client::Filesystem;
+    use nix::unistd::{getgid, getuid};
+    use tokio::sync::mpsc;
+    use tracing::error;
+
+    tracing_subscriber::fmt::init();
+    let args = Args::parse();
+
+    let key = rendezvous::parse_key(&args.key).ok_or_else(|| anyhow!("bad key"))?;
+    let fsys = if args.reconnect {
+        let (addr, name) = (args.connect.clone(), args.name.clone());
+        Filesystem::resilient_with_max_in_flight(move || {
+            let (addr, name) = (addr.clone(), name.clone());
+            async move { connect(addr.as_deref(), &name, &key).await }
+        }, args.max_in_flight).await
+    } else {
+        async {
+            let transport = connect(args.connect.as_deref(), &args.name, &key).await?;
+            Filesystem::with_max_in_flight(transport, args.max_in_flight).await
+        }.await
+    }.with_context(|| format!("couldn't connect to {}", args.connect.as_deref().unwrap_or(&args.name)))?;
+    let root = fsys.attach(&args.uname, &args.aname).await?;
+
+    let fs = Arc::new(fs::NineFs::new(root, getuid().as_raw(), getgid().as_raw()).await?);
+    let session = Arc::new(fuse::Session::mount(&args.mountpoint)
+        .with_context(|| format!("couldn't mount on {}", args.mountpoint.display()))?);
+    println!("mounted on {}", args.mountpoint.display());
+
+    // Reading from /dev/fuse blocks, so it gets a thread of its own
+    let (requests_tx, mut requests) = mpsc::unbounded_channel();
+    std::thread::spawn({
+        let session = session.clone();
+        move || {
+            let mut buf = Vec::new();
+            loop {
+                match session.receive(&mut buf) {
+                    Ok(Some(req)) => if requests_tx.send(req).is_err() {
+                        break;
+                    },
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!("lost the kernel connection: {e}");
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    loop {
+        tokio::select! {
+            req = requests.recv() => {
+                let Some(req) = req else { break };
+                let (fs, session) = (fs.clone(), session.clone());
+                tokio::spawn(async move { fs.handle(&session, req).await });
+            },
+            _ = tokio::signal::ctrl_c() => {
+                session.unmount()?;
+                break;
+            },
+            _ = fsys.closed() => {
+                error!("lost the connection: {:?}", fsys.health());
+                session.unmount()?;
+                break;
+            }
+        }
+    }
+
+    println!("unmounted");
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() -> anyhow::Result<()> {
+    let _ = Args::parse();
+    anyhow::bail!("mounting is only supported on Linux")
+}
@@ -92,6 +92,13 @@ pub const DMEXCL: u32 = 0x20000000;
 pub const DMAUTH: u32 = 0x08000000;
 pub const DMTMP: u32 = 0x04000000;
 
+pub const OREAD: u8 = 0x00; /* open for read */
+pub const OWRITE: u8 = 0x01; /* write */
+pub const ORDWR: u8 = 0x02; /* read and write */
+pub const OEXEC: u8 = 0x03; /* execute, == read but check execute permission */
+pub const OTRUNC: u8 = 0x10; /* or'ed in (except for exec), truncate file first */
+pub const ORCLOSE: u8 = 0x40; /* or'ed in, remove on close */
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Qid {
     pub type_: u8,
@@ -0,0 +1,64 @@
+#![forbid(unsafe_code)]
+
+//! Reads one file from a server over and over, with more and more Treads out at once.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context};
+use clap::Parser;
+use client::{FileReader, Filesystem};
+use tokio::io::AsyncReadExt as _;
+
+#[derive(Debug, Parser)]
+#[command(about = "Measures how read-ahead speeds up reading a file")]
+struct Args {
+    /// The server, as host:port
+    connect: String,
+    /// The file to read, from the root
+    path: String,
+    /// The server's public key, as 64 hex digits
+    #[arg(long, env = "SERVER_KEY")]
+    key: String,
+    /// Times to read the file at each read-ahead
+    #[arg(long, default_value_t = 5)]
+    rounds: u32,
+    /// Read-aheads to try
+    #[arg(long, value_delimiter = ',', default_value = "1,2,4,8,16")]
+    read_ahead: Vec<usize>
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    let key = rendezvous::parse_key(&args.key).ok_or_else(|| anyhow!("bad key"))?;
+    let transport = rendezvous::connect_direct(&args.connect, &key).await
+        .with_context(|| format!("couldn't connect to {}", args.connect))?;
+    let fsys = Filesystem::new(transport).await?;
+    let root = fsys.attach("anonymous", "").await?;
+    let file = root.open_at(&args.path).await?;
+
+    let mut first: Option<Vec<u8>> = None;
+    let mut contents = Vec::new();
+    for &read_ahead in &args.read_ahead {
+        let mut elapsed = Duration::ZERO;
+        for _ in 0..args.rounds {
+            contents.clear();
+            let t = Instant::now();
+            FileReader::with_read_ahead(&file, read_ahead).read_to_end(&mut contents).await?;
+            elapsed += t.elapsed();
+
+            match &first {
+                Some(first) if *first != contents => anyhow::bail!("read different contents with read-ahead {read_ahead}"),
+                Some(_) => (),
+                None => first = Some(contents.clone())
+            }
+        }
+
+        let per_round = elapsed / args.rounds.max(1);
+        let mib_per_sec = contents.len() as f64 / per_round.as_secs_f64() / (1024. * 1024.);
+        println!("read-ahead {read_ahead:>3}: {} bytes in {per_round:.2?}, {mib_per_sec:.2} MiB/s", contents.len());
+    }
+
+    Ok(())
+}
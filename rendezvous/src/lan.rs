@@ -0,0 +1,323 @@
+//! Finding servers on the LAN without a mediator: servers multicast their name, key and
+//! the port they accept direct connections on, and clients listen for that.
+//!
+//! Announcements go to an IPv4 group, scoped to the organization so routers don't
+//! forward it, and to a link-local IPv6 group. Loopback takes IPv4 multicast, so both
+//! ends can run on one host, or only there (see [`Options::loopback`]).
+//!
+//! Nothing authenticates an announcement: anyone on the LAN can claim any name. Pin the
+//! key (see [`Mediator::connect_by_name_pinned`](crate::Mediator::connect_by_name_pinned))
+//! if that matters.
+
+use std::{collections::HashMap, io, net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6}, pin::pin, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use mediator_proto::Announcement;
+use prost::Message;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use tokio::{net::UdpSocket, sync::Notify, task::JoinSet, time};
+use tracing::{debug, warn};
+
+pub const GROUP_V4: Ipv4Addr = Ipv4Addr::new(239, 255, 57, 87);
+pub const GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x5787);
+pub const PORT: u16 = 64345;
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+
+// Peers are forgotten after missing this many announcements
+const MISSED_ANNOUNCEMENTS: u32 = 5;
+const MAGIC: &[u8; 4] = b"9wl1";
+const MAX_ANNOUNCEMENT: usize = 512;
+
+/// Where to announce and listen.
+#[derive(Debug, Clone)]
+pub struct Options {
+    loopback: bool,
+    port: u16
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self { loopback: false, port: PORT }
+    }
+}
+
+impl Options {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only multicast over IPv4 loopback, so nothing leaves this host.
+    pub fn loopback(mut self, loopback: bool) -> Self {
+        self.loopback = loopback;
+        self
+    }
+
+    /// The port announcements go to, [`PORT`] by default.
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+}
+
+/// The interfaces to multicast on: IPv4 ones by address, IPv6 ones by index.
+fn interfaces(options: &Options) -> (Vec<Ipv4Addr>, Vec<u32>) {
+    if options.loopback {
+        // Linux won't take IPv6 multicast on loopback
+        return (vec![Ipv4Addr::LOCALHOST], Vec::new());
+    }
+    system_interfaces()
+}
+
+#[cfg(unix)]
+fn system_interfaces() -> (Vec<Ipv4Addr>, Vec<u32>) {
+    use nix::{ifaddrs::getifaddrs, net::if_::{if_nametoindex, InterfaceFlags}};
+
+    let Ok(addrs) = getifaddrs() else {
+        return (vec![Ipv4Addr::UNSPECIFIED], vec![0]);
+    };
+    let (mut v4, mut v6) = (Vec::new(), Vec::new());
+    for ifa in addrs {
+        if !ifa.flags.contains(InterfaceFlags::IFF_UP) {
+            continue;
+        }
+        let multicast = ifa.flags.contains(InterfaceFlags::IFF_MULTICAST);
+        let Some(addr) = ifa.address else { continue };
+        // Linux doesn't flag loopback as multicast-capable, but IPv4 multicast works on it
+        if let Some(sin) = addr.as_sockaddr_in() && (multicast || ifa.flags.contains(InterfaceFlags::IFF_LOOPBACK)) {
+            v4.push(sin.ip());
+        } else if addr.as_sockaddr_in6().is_some() && multicast
+            && let Ok(index) = if_nametoindex(ifa.interface_name.as_str())
+            && !v6.contains(&index)
+        {
+            v6.push(index);
+        }
+    }
+    (v4, v6)
+}
+
+/// Whichever ones the system picks.
+#[cfg(not(unix))]
+fn system_interfaces() -> (Vec<Ipv4Addr>, Vec<u32>) {
+    (vec![Ipv4Addr::UNSPECIFIED], vec![0])
+}
+
+fn socket(domain: Domain) -> io::Result<Socket> {
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Announces `name` every [`ANNOUNCE_INTERVAL`] until dropped. `port` is where the
+/// server accepts direct connections, see [`transport::SecureTransport::accept`].
+pub async fn announce(name: &str, public_key: &[u8; 32], port: u16) -> io::Result<()> {
+    announce_with(name, public_key, port, &Options::new()).await
+}
+
+/// Like [`announce`], but where `options` say.
+pub async fn announce_with(name: &str, public_key: &[u8; 32], port: u16, options: &Options) -> io::Result<()> {
+    let mut msg = MAGIC.to_vec();
+    Announcement {
+        name: name.to_owned(),
+        pubkey: public_key.to_vec(),
+        port: port.into()
+    }.encode(&mut msg).map_err(io::Error::other)?;
+
+    let v4 = socket(Domain::IPV4)?;
+    v4.set_multicast_ttl_v4(1)?;
+    v4.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)).into())?;
+    let v4 = UdpSocket::from_std(v4.into())?;
+    // IPv6 may well be disabled
+    let v6 = socket(Domain::IPV6).and_then(|v6| {
+        v6.set_multicast_hops_v6(1)?;
+        v6.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)).into())?;
+        UdpSocket::from_std(v6.into())
+    }).inspect_err(|e| debug!("not announcing over IPv6: {e}")).ok();
+
+    let mut interval = time::interval(ANNOUNCE_INTERVAL);
+    loop {
+        interval.tick().await;
+        // Interfaces come and go, so look again every time
+        let (v4_ifs, v6_ifs) = interfaces(options);
+        for addr in v4_ifs {
+            if let Err(e) = SockRef::from(&v4).set_multicast_if_v4(&addr) {
+                debug!("can't announce on {addr}: {e}");
+                continue;
+            }
+            if let Err(e) = v4.send_to(&msg, (GROUP_V4, options.port)).await {
+                debug!("can't announce on {addr}: {e}");
+            }
+        }
+        let Some(v6) = &v6 else { continue };
+        for index in v6_ifs {
+            if let Err(e) = v6.send_to(&msg, SocketAddrV6::new(GROUP_V6, options.port, 0, index)).await {
+                debug!("can't announce on interface {index}: {e}");
+            }
+        }
+    }
+}
+
+/// A server found on the LAN.
+#[derive(Debug, Clone)]
+pub struct LanPeer {
+    pub public_key: [u8; 32],
+    /// Where it announced from, on its announced port
+    pub addrs: Vec<SocketAddr>
+}
+
+#[derive(Debug)]
+struct Seen {
+    public_key: [u8; 32],
+    addrs: HashMap<SocketAddr, Instant>
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    peers: Mutex<HashMap<String, Seen>>,
+    announced: Notify
+}
+
+/// Listens for announcements in the background, until dropped.
+#[derive(Debug)]
+pub struct Discovery {
+    shared: Arc<Shared>,
+    _tasks: JoinSet<()>
+}
+
+impl Discovery {
+    /// Starts listening, on every interface that's up by now. Must be called within a
+    /// Tokio runtime.
+    pub fn start() -> io::Result<Self> {
+        Self::start_with(&Options::new())
+    }
+
+    /// Like [`Discovery::start`], but where `options` say.
+    pub fn start_with(options: &Options) -> io::Result<Self> {
+        let (v4_ifs, v6_ifs) = interfaces(options);
+        let shared = Arc::new(Shared::default());
+        let mut tasks = JoinSet::new();
+
+        let v4 = socket(Domain::IPV4)?;
+        v4.set_reuse_address(true)?;
+        v4.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, options.port)).into())?;
+        for addr in v4_ifs {
+            if let Err(e) = v4.join_multicast_v4(&GROUP_V4, &addr) {
+                debug!("can't listen for announcements on {addr}: {e}");
+            }
+        }
+        tasks.spawn(receive(UdpSocket::from_std(v4.into())?, shared.clone()));
+
+        if v6_ifs.is_empty() {
+            return Ok(Self { shared, _tasks: tasks });
+        }
+        let v6 = socket(Domain::IPV6).and_then(|v6| {
+            v6.set_only_v6(true)?;
+            v6.set_reuse_address(true)?;
+            v6.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, options.port)).into())?;
+            for &index in &v6_ifs {
+                if let Err(e) = v6.join_multicast_v6(&GROUP_V6, index) {
+                    debug!("can't listen for announcements on interface {index}: {e}");
+                }
+            }
+            UdpSocket::from_std(v6.into())
+        });
+        match v6 {
+            Ok(v6) => { tasks.spawn(receive(v6, shared.clone())); },
+            Err(e) => debug!("not listening for announcements over IPv6: {e}")
+        }
+
+        Ok(Self { shared, _tasks: tasks })
+    }
+
+    /// Looks up `name`, waiting up to `timeout` for it to be announced if it hasn't been
+    /// lately.
+    pub async fn resolve(&self, name: &str, timeout: Duration) -> Option<LanPeer> {
+        time::timeout(timeout, async {
+            loop {
+                // Register interest first, so an announcement can't slip in between
+                let mut announced = pin!(self.shared.announced.notified());
+                announced.as_mut().enable();
+                if let Some(peer) = self.lookup(name) {
+                    return peer;
+                }
+                announced.await;
+            }
+        }).await.ok()
+    }
+
+    fn lookup(&self, name: &str) -> Option<LanPeer> {
+        let mut peers = self.shared.peers.lock().unwrap();
+        let seen = peers.get_mut(name)?;
+        seen.addrs.retain(|_, last| last.elapsed() < ANNOUNCE_INTERVAL * MISSED_ANNOUNCEMENTS);
+        if seen.addrs.is_empty() {
+            peers.remove(name);
+            return None;
+        }
+        Some(LanPeer {
+            public_key: seen.public_key,
+            addrs: seen.addrs.keys().copied().collect()
+        })
+    }
+}
+
+fn parse(msg: &[u8], from: SocketAddr) -> Option<(String, [u8; 32], SocketAddr)> {
+    let ann = Announcement::decode(msg.strip_prefix(MAGIC)?).ok()?;
+    let public_key = ann.pubkey[..].try_into().ok()?;
+    let port = u16::try_from(ann.port).ok().filter(|&p| p != 0)?;
+    let addr = match from {
+        // Keep the scope, link-local addresses are useless without it
+        SocketAddr::V6(from) if from.ip().to_ipv4_mapped().is_none() => SocketAddrV6::new(*from.ip(), port, 0, from.scope_id()).into(),
+        from => SocketAddr::new(from.ip().to_canonical(), port)
+    };
+    Some((ann.name, public_key, addr))
+}
+
+async fn receive(socket: UdpSocket, shared: Arc<Shared>) {
+    let mut buf = [0; MAX_ANNOUNCEMENT];
+    loop {
+        let (n, from) = match socket.recv_from(&mut buf).await {
+            Ok(res) => res,
+            Err(e) => {
+                warn!("stopped listening for announcements: {e}");
+                return;
+            }
+        };
+        let Some((name, public_key, addr)) = parse(&buf[..n], from) else {
+            debug!("ignoring a bad announcement from {from}");
+            continue;
+        };
+
+        let mut peers = shared.peers.lock().unwrap();
+        let seen = peers.entry(name).or_insert_with(|| Seen { public_key, addrs: HashMap::new() });
+        // Whoever announced last has the name, as far as we can tell
+        if seen.public_key != public_key {
+            *seen = Seen { public_key, addrs: HashMap::new() };
+        }
+        seen.addrs.insert(addr, Instant::now());
+        drop(peers);
+        shared.announced.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn resolves_announcements_over_loopback() {
+        // Away from PORT, and from any other test run
+        let options = Options::new().loopback(true).port(40000 + (std::process::id() % 20000) as u16);
+        let discovery = Discovery::start_with(&options).unwrap();
+        let key = [7; 32];
+        let announcing = tokio::spawn({
+            let options = options.clone();
+            async move { announce_with("test", &key, 5000, &options).await }
+        });
+
+        let peer = discovery.resolve("test", ANNOUNCE_INTERVAL * 2).await.expect("announcement not heard");
+        assert_eq!(peer.public_key, key);
+        assert_eq!(peer.addrs, [SocketAddr::from((Ipv4Addr::LOCALHOST, 5000))]);
+        assert!(discovery.resolve("other", Duration::from_millis(100)).await.is_none());
+
+        announcing.abort();
+    }
+}
@@ -0,0 +1,279 @@
+#![forbid(unsafe_code)]
+
+//! Finding peers through a mediator: registering a name and accepting the peers that ask
+//! for it, or connecting to a name. Optionally, finding them on the LAN first (see [`lan`]).
+
+use std::{fmt::{self, Debug}, io, net::{Ipv4Addr, Ipv6Addr, SocketAddr}, sync::Arc, time::Duration};
+
+use mediator_proto::{candidate, mediator_client::MediatorClient, Candidate, RendezvousRequest};
+use thiserror::Error;
+use tokio::time;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity};
+use tracing::warn;
+use transport::{SecureTransport, Side, DIRECT_TIMEOUT};
+use udt::SocketOptions;
+use util::{addr_scope, AddrScope};
+
+pub mod lan;
+mod register;
+
+pub use mediator_proto::{Endpoint, ServiceInfo};
+pub use register::IncomingPeer;
+
+const DEFAULT_URL: &str = "http://[::1]:64344";
+const CONNECT_ATTEMPTS: u32 = 3;
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("couldn't reach the mediator: {0}")]
+    Unreachable(#[from] tonic::transport::Error),
+    #[error("mediator: {}", .0.message())]
+    Mediator(#[from] tonic::Status),
+    #[error("bad reply from the mediator: {0}")]
+    BadReply(&'static str),
+    #[error("the peer's key isn't the expected one")]
+    KeyMismatch,
+    #[error(transparent)]
+    Io(#[from] io::Error)
+}
+
+impl Error {
+    /// Whether trying again later could help
+    fn is_transient(&self) -> bool {
+        use tonic::Code;
+        match self {
+            Self::Unreachable(_) => true,
+            Self::Mediator(status) => matches!(status.code(),
+                // AlreadyExists: our last registration hasn't gone away yet
+                Code::Unavailable | Code::DeadlineExceeded | Code::Cancelled | Code::Unknown | Code::AlreadyExists
+            ),
+            _ => false
+        }
+    }
+}
+
+/// A server's static key pair
+#[derive(Clone)]
+pub struct Keypair {
+    pub public: [u8; 32],
+    pub private: [u8; 32]
+}
+
+impl Debug for Keypair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keypair")
+            .field("public", &self.public)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A fresh dual-stack UDT endpoint on `port` (or IPv4 only, if IPv6 is disabled).
+pub fn bind_udt(port: u16) -> io::Result<udt::Endpoint> {
+    match udt::Endpoint::bind_with_options((Ipv6Addr::UNSPECIFIED, port).into(), SocketOptions::new().ipv6_only(false)) {
+        Ok(socket) => Ok(socket),
+        Err(_) => udt::Endpoint::bind((Ipv4Addr::UNSPECIFIED, port).into())
+    }
+}
+
+/// Parses a public key written as 64 hex digits.
+pub fn parse_key(hex: &str) -> Option<[u8; 32]> {
+    let digits = hex.as_bytes();
+    // from_str_radix would take a sign too
+    if digits.len() != 64 || !digits.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    let mut key = [0; 32];
+    for (b, pair) in key.iter_mut().zip(digits.chunks(2)) {
+        *b = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(key)
+}
+
+/// Connects to a peer listening at `addr` (`host:port`), skipping the mediator.
+pub async fn connect_direct(addr: &str, remote_public_key: &[u8; 32]) -> io::Result<SecureTransport> {
+    let addrs = tokio::net::lookup_host(addr).await?.collect::<Vec<_>>();
+    let socket = Arc::new(bind_udt(0)?);
+    time::timeout(DIRECT_TIMEOUT, SecureTransport::dial(&socket, &addrs, remote_public_key)).await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+}
+
+/// A mediator, and the UDT endpoint we rendezvous on.
+#[derive(Debug, Clone)]
+pub struct Mediator {
+    uri: tonic::transport::Endpoint,
+    socket: Arc<udt::Endpoint>,
+    lan: Option<Arc<lan::Discovery>>
+}
+
+impl Mediator {
+    pub fn new(uri: impl Into<String>, socket: Arc<udt::Endpoint>) -> Result<Self, Error> {
+        Ok(Self {
+            uri: tonic::transport::Endpoint::from_shared(uri.into())?,
+            socket,
+            lan: None
+        })
+    }
+
+    /// Like [`Mediator::new`], on a fresh dual-stack endpoint (or IPv4 only, if IPv6 is
+    /// disabled).
+    pub fn bind(uri: impl Into<String>) -> Result<Self, Error> {
+        Self::new(uri, Arc::new(bind_udt(0)?))
+    }
+
+    /// Like [`Mediator::bind`], configured by the environment:
+    /// - `MEDIATOR_URL`: defaults to `http://[::1]:64344`. An `https` URL means TLS.
+    /// - `MEDIATOR_CA`: PEM certificates to trust for the mediator, instead of the system's
+    /// - `MEDIATOR_CERT` and `MEDIATOR_KEY`: a PEM client certificate and its key, for
+    ///   mediators that only let known servers register
+    /// - `LAN_DISCOVERY`: if set, looks for names on the LAN before asking the mediator
+    ///
+    /// Must be called within a Tokio runtime.
+    pub fn from_env() -> Result<Self, Error> {
+        let uri = std::env::var("MEDIATOR_URL").unwrap_or_else(|_| DEFAULT_URL.to_owned());
+        let tls = uri.starts_with("https:");
+        let mut mediator = Self::bind(uri)?;
+        if std::env::var_os("LAN_DISCOVERY").is_some() {
+            mediator = mediator.lan_discovery(Arc::new(lan::Discovery::start()?));
+        }
+        if !tls {
+            return Ok(mediator);
+        }
+
+        let mut tls = match std::env::var_os("MEDIATOR_CA") {
+            Some(ca) => ClientTlsConfig::new().ca_certificate(Certificate::from_pem(std::fs::read(ca)?)),
+            None => ClientTlsConfig::new().with_native_roots()
+        };
+        if let (Some(cert), Some(key)) = (std::env::var_os("MEDIATOR_CERT"), std::env::var_os("MEDIATOR_KEY")) {
+            tls = tls.identity(Identity::from_pem(std::fs::read(cert)?, std::fs::read(key)?));
+        }
+        mediator.tls_config(tls)
+    }
+
+    /// Talks to the mediator over TLS.
+    pub fn tls_config(mut self, tls: ClientTlsConfig) -> Result<Self, Error> {
+        self.uri = self.uri.tls_config(tls)?;
+        Ok(self)
+    }
+
+    /// Looks for names on the LAN before asking the mediator.
+    pub fn lan_discovery(mut self, discovery: Arc<lan::Discovery>) -> Self {
+        self.lan = Some(discovery);
+        self
+    }
+
+    pub fn socket(&self) -> &Arc<udt::Endpoint> {
+        &self.socket
+    }
+
+    /// Our addresses worth advertising, on every interface that can reach beyond this host.
+    fn candidates(&self) -> io::Result<Vec<Candidate>> {
+        let local_addr = self.socket.local_addr()?;
+        let candidates = local_ip_address::list_afinet_netifas().map_err(io::Error::other)?.into_iter()
+            .filter(|(_, addr)| local_addr.is_ipv6() || addr.is_ipv4())
+            .filter_map(|(_, addr)| Some(Candidate::new(
+                match addr_scope(&addr)? {
+                    AddrScope::Global => candidate::Kind::Host,
+                    AddrScope::Lan => candidate::Kind::Lan
+                },
+                SocketAddr::new(addr, local_addr.port())
+            )))
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "no usable address"));
+        }
+        Ok(candidates)
+    }
+
+    async fn client(&self) -> Result<MediatorClient<Channel>, Error> {
+        let mut backoff = MIN_BACKOFF;
+        for _ in 1..CONNECT_ATTEMPTS {
+            match self.uri.connect().await {
+                Ok(channel) => return Ok(MediatorClient::new(channel)),
+                Err(e) => warn!("couldn't reach the mediator, retrying in {backoff:?}: {e}")
+            }
+            time::sleep(backoff).await;
+            backoff *= 2;
+        }
+        Ok(MediatorClient::new(self.uri.connect().await?))
+    }
+
+    /// Connects to whoever registered `name`, trusting the mediator about their key.
+    pub async fn connect_by_name(&self, name: &str) -> Result<SecureTransport, Error> {
+        self.connect(name, None).await
+    }
+
+    /// Connects to whoever registered `name`, if their key is `remote_public_key`.
+    pub async fn connect_by_name_pinned(&self, name: &str, remote_public_key: &[u8; 32]) -> Result<SecureTransport, Error> {
+        self.connect(name, Some(remote_public_key)).await
+    }
+
+    async fn connect(&self, name: &str, pinned: Option<&[u8; 32]>) -> Result<SecureTransport, Error> {
+        if let Some(transport) = self.connect_lan(name, pinned).await {
+            return Ok(transport);
+        }
+
+        let candidates = self.candidates()?;
+        let resp = self.client().await?.rendezvous(RendezvousRequest {
+            name: name.to_owned(),
+            endpoint: Some(Endpoint {
+                pubkey: Vec::new(),
+                candidates
+            })
+        }).await?.into_inner();
+
+        let ep = resp.endpoint.ok_or(Error::BadReply("no endpoint"))?;
+        let key = <[u8; 32]>::try_from(&ep.pubkey[..]).map_err(|_| Error::BadReply("bad public key"))?;
+        if pinned.is_some_and(|pinned| *pinned != key) {
+            return Err(Error::KeyMismatch);
+        }
+        let addrs = ep.connect_order();
+        let relay = resp.relay.as_ref().and_then(|r| Some((r.socket_addr()?, &r.token[..])));
+        if addrs.is_empty() && relay.is_none() {
+            return Err(Error::BadReply("no way to reach the peer"));
+        }
+
+        Ok(SecureTransport::connect_or_relay(&self.socket, &addrs, relay, Side::Initiator { remote_public_key: &key }).await?)
+    }
+
+    /// Connects to `name` if it's announced on the LAN, giving up quietly otherwise.
+    async fn connect_lan(&self, name: &str, pinned: Option<&[u8; 32]>) -> Option<SecureTransport> {
+        // Someone who just started listening may not have heard the announcement yet
+        let peer = self.lan.as_ref()?.resolve(name, lan::ANNOUNCE_INTERVAL).await?;
+        if pinned.is_some_and(|pinned| *pinned != peer.public_key) {
+            warn!("{name} on the LAN has the wrong key, ignoring it");
+            return None;
+        }
+        let dial = SecureTransport::dial(&self.socket, &peer.addrs, &peer.public_key);
+        match time::timeout(DIRECT_TIMEOUT, dial).await {
+            Ok(Ok(transport)) => Some(transport),
+            Ok(Err(e)) => {
+                warn!("couldn't reach {name} on the LAN, asking the mediator: {e}");
+                None
+            },
+            Err(_) => {
+                warn!("timed out reaching {name} on the LAN, asking the mediator");
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_only_hex_keys() {
+        let hex = "f101e400f7a3f8425e397a1e3bb7921627911a8882916f571302da7411524728";
+        let key = parse_key(hex).unwrap();
+        assert_eq!(key[..3], [0xf1, 0x01, 0xe4]);
+        assert_eq!(parse_key(&hex.to_uppercase()), Some(key));
+
+        assert_eq!(parse_key(&hex[2..]), None);
+        assert_eq!(parse_key(&format!("+1{}", &hex[2..])), None);
+        assert_eq!(parse_key(&format!("{}-1", &hex[..62])), None);
+        assert_eq!(parse_key(&format!("{}g0", &hex[..62])), None);
+    }
+}
@@ -0,0 +1,163 @@
+use std::{collections::HashMap, io, sync::Arc, time::Duration};
+
+use futures::Stream;
+use mediator_proto::{challenge, ip_from_bytes, register_request, Endpoint, Heartbeat, RegisterReply, RegisterRequest, Registration, Relay, ServiceInfo};
+use tokio::{sync::{mpsc, oneshot}, time};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Status, Streaming};
+use tracing::{info, warn};
+use transport::{SecureTransport, Side};
+
+use crate::{Error, Keypair, Mediator, MAX_BACKOFF, MIN_BACKOFF};
+
+// Room for a few approvals and denials in flight
+const REQUEST_BUF: usize = 16;
+// How long the mediator gets to offer a relay once we approve. It answers right away.
+const RELAY_OFFER_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn hung_up() -> Error {
+    Status::unavailable("mediator hung up").into()
+}
+
+/// A peer asking to rendezvous with us. Dropping it denies the request.
+#[derive(Debug)]
+pub struct IncomingPeer {
+    request_id: u64,
+    endpoint: Endpoint,
+    // The relay session the mediator offers once we approve
+    relay: oneshot::Receiver<Option<Relay>>,
+    requests: Option<mpsc::Sender<RegisterRequest>>,
+    socket: Arc<udt::Endpoint>,
+    keys: Keypair
+}
+
+impl IncomingPeer {
+    /// The peer's endpoint, as it claimed it (plus what the mediator observed).
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    pub async fn accept(mut self) -> io::Result<SecureTransport> {
+        let Some(requests) = self.requests.take() else { unreachable!() };
+        requests.send(RegisterRequest { req: Some(register_request::Req::ApproveId(self.request_id)) }).await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "registration is gone"))?;
+        let relay = time::timeout(RELAY_OFFER_TIMEOUT, &mut self.relay).await.ok().and_then(Result::ok).flatten();
+        let addrs = self.endpoint.connect_order();
+        let relay = relay.as_ref().and_then(|r| Some((r.socket_addr()?, &r.token[..])));
+        SecureTransport::connect_or_relay(&self.socket, &addrs, relay, Side::Responder { local_private_key: &self.keys.private }).await
+    }
+
+    /// Same as dropping it
+    pub fn deny(self) {}
+}
+
+impl Drop for IncomingPeer {
+    fn drop(&mut self) {
+        // Best effort: if the registration is gone, so is the request
+        if let Some(requests) = self.requests.take() {
+            let _ = requests.try_send(RegisterRequest { req: Some(register_request::Req::DenyId(self.request_id)) });
+        }
+    }
+}
+
+impl Mediator {
+    /// Registers `name` for `keys`, and yields every peer that asks for it.
+    ///
+    /// If the mediator goes away, this registers again once it's back. It only ends if the
+    /// mediator refuses the registration for good, e.g. because the name belongs to
+    /// someone else.
+    pub fn register(&self, name: impl Into<String>, keys: Keypair, info: Option<ServiceInfo>) -> impl Stream<Item = Result<IncomingPeer, Error>> + Send + 'static {
+        let this = self.clone();
+        let name = name.into();
+        async_stream::stream! {
+            let mut backoff = MIN_BACKOFF;
+            loop {
+                let e = match this.register_once(&name, &keys, info.clone()).await {
+                    Ok((mut replies, requests)) => {
+                        backoff = MIN_BACKOFF;
+                        let mut relays = HashMap::<u64, oneshot::Sender<Option<Relay>>>::new();
+                        loop {
+                            match replies.message().await {
+                                Ok(Some(RegisterReply { request_id, endpoint: Some(endpoint), .. })) => {
+                                    let (relay_to, relay) = oneshot::channel();
+                                    relays.retain(|_, relay_to| !relay_to.is_closed());
+                                    relays.insert(request_id, relay_to);
+                                    yield Ok(IncomingPeer {
+                                        request_id,
+                                        endpoint,
+                                        relay,
+                                        requests: Some(requests.clone()),
+                                        socket: this.socket.clone(),
+                                        keys: keys.clone()
+                                    });
+                                },
+                                // What the mediator has to say about a request we approved
+                                Ok(Some(RegisterReply { request_id, endpoint: None, relay, .. })) if request_id != 0 => {
+                                    if let Some(relay_to) = relays.remove(&request_id) {
+                                        let _ = relay_to.send(relay);
+                                    }
+                                },
+                                Ok(Some(_)) => (),
+                                Ok(None) => break hung_up(),
+                                Err(status) => break status.into()
+                            }
+                        }
+                    },
+                    Err(e) => e
+                };
+                if !e.is_transient() {
+                    yield Err(e);
+                    break;
+                }
+                warn!("registration lost, retrying in {backoff:?}: {e}");
+                time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+
+    async fn register_once(&self, name: &str, keys: &Keypair, info: Option<ServiceInfo>) -> Result<(Streaming<RegisterReply>, mpsc::Sender<RegisterRequest>), Error> {
+        let (requests, rx) = mpsc::channel(REQUEST_BUF);
+        requests.try_send(RegisterRequest {
+            req: Some(register_request::Req::Registration(Registration {
+                name: name.to_owned(),
+                endpoint: Some(Endpoint {
+                    pubkey: keys.public.to_vec(),
+                    candidates: self.candidates()?
+                }),
+                info
+            }))
+        }).map_err(|_| hung_up())?;
+        let mut replies = self.client().await?.register(ReceiverStream::new(rx)).await?.into_inner();
+
+        // Prove we own the name
+        let challenge = replies.message().await?.ok_or_else(hung_up)?.challenge;
+        requests.send(RegisterRequest {
+            req: Some(register_request::Req::ChallengeResponse(challenge::respond(name, &keys.private, &challenge)?))
+        }).await.map_err(|_| hung_up())?;
+
+        let accepted = replies.message().await?.ok_or_else(hung_up)?;
+        if let Some(ip) = ip_from_bytes(&accepted.observed_addr) {
+            info!("mediator sees us as {ip}");
+        }
+
+        // Keep the registration alive. This stops once the registration ends, since
+        // that drops the receiving end.
+        let heartbeat_interval = Duration::from_secs(accepted.heartbeat_interval.max(1).into());
+        tokio::spawn({
+            let requests = requests.clone();
+            async move {
+                let mut interval = time::interval(heartbeat_interval);
+                loop {
+                    interval.tick().await;
+                    let heartbeat = RegisterRequest { req: Some(register_request::Req::Heartbeat(Heartbeat {})) };
+                    if requests.send(heartbeat).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok((replies, requests))
+    }
+}
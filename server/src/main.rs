@@ -1,18 +1,17 @@
 #![forbid(unsafe_code)]
 
-use std::{collections::HashMap, error::Error, future::ready, net::{IpAddr, Ipv6Addr, SocketAddrV6}, path::PathBuf, pin::pin, sync::{atomic::{AtomicU64, Ordering}, Arc}};
+use std::{collections::HashMap, future::ready, path::PathBuf, pin::pin, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::Duration};
 
-use anyhow::{anyhow, bail};
+use anyhow::{bail, Context};
 use bytestring::ByteString;
-use futures::{stream::abortable, StreamExt};
-use mediator_proto::{mediator_client::MediatorClient, register_request, RegisterReply, RegisterRequest, Registration};
+use futures::{stream::{self, abortable}, StreamExt};
 use np::traits;
-use tokio::sync::mpsc;
-use tokio_stream::{wrappers::ReceiverStream};
-use transport::{SecureTransport, Side};
-use util::is_unicast_global;
+use rendezvous::ServiceInfo;
+use tokio::time;
+use transport::SecureTransport;
 
 mod np;
+mod policy;
 mod res;
 
 type ShareTable = HashMap<Arc<str>, PathBuf>;
@@ -73,81 +72,127 @@ impl traits::Serve for Handler {
 
 const PRIVATE_KEY: [u8; 32] = [127, 93, 161, 223, 213, 211, 245, 80, 69, 165, 77, 133, 169, 40, 130, 112, 218, 255, 225, 74, 78, 69, 83, 20, 154, 244, 58, 224, 51, 34, 61, 102];
 const PUBLIC_KEY: [u8; 32] = [241, 1, 228, 0, 247, 163, 248, 66, 94, 57, 122, 30, 59, 183, 146, 22, 39, 145, 26, 136, 130, 145, 111, 87, 19, 2, 218, 116, 17, 82, 71, 40];
+const NAME: &str = "bugerking";
+const DIRECT_BACKLOG: u32 = 64;
+// Failing to accept, say for lack of fds, tends to go on for a while
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(5);
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
     // console_subscriber::init();
 
-    let Some(addr) = local_ip_address::unix::list_afinet_netifas()?.into_iter()
-        .find_map(|(_, addr)| match addr {
-            IpAddr::V6(addr) if is_unicast_global(&addr) => Some(addr),
-            _ => None
-        }) else { bail!("no usable address :(") };
-    
-    let endpoint = Arc::new(udt::Endpoint::bind("[::]:0".parse()?)?);
-
-    println!("bound to [{addr:?}]:{}", endpoint.local_addr()?.port());
-
-    let mut mediator = MediatorClient::connect("http://[::1]:64344").await?;
+    // Peers that know our address and key can connect directly, without a mediator
+    let listen_port = match std::env::var("LISTEN_PORT") {
+        Ok(port) => Some(port.parse::<u16>().context("bad LISTEN_PORT")?),
+        Err(_) => None
+    };
+    let lan = std::env::var_os("LAN_DISCOVERY").is_some();
+    let no_mediator = std::env::var_os("NO_MEDIATOR").is_some();
+    if no_mediator && listen_port.is_none() && !lan {
+        bail!("NO_MEDIATOR needs LISTEN_PORT or LAN_DISCOVERY, or nobody could connect");
+    }
 
-    let (registration, r2) = mpsc::channel(1);
-    let incoming = mediator.register(ReceiverStream::new(r2)).await?.into_inner();
-    println!("brug");
+    let policy = policy::Policy::from_env()?;
 
-    registration.send(RegisterRequest {
-        req: Some(register_request::Req::Registration(Registration {
-            name: "bugerking".to_owned(),
-            endpoint: Some(mediator_proto::Endpoint {
-                addr: addr.octets().to_vec(),
-                port: endpoint.local_addr()?.port().into(),
-                pubkey: PUBLIC_KEY.to_vec()
+    let shares: ShareTable = [
+        ("forfun".into(), "forfun".into()),
+        ("ff2".into(), "forfun".into())
+    ].into_iter().collect();
+
+    let peers = if no_mediator {
+        stream::empty().right_stream()
+    } else {
+        let mediator = rendezvous::Mediator::from_env()?;
+        println!("bound to {}", mediator.socket().local_addr()?);
+        mediator.register(
+            NAME,
+            rendezvous::Keypair { public: PUBLIC_KEY, private: PRIVATE_KEY },
+            Some(ServiceInfo {
+                description: "the bugerking's files".to_owned(),
+                shares: shares.keys().map(|s| s.to_string()).collect()
             })
-        }))
-    }).await.map_err(|_| anyhow!("bruh moment"))?;
-
-    let listener = incoming
-        .filter_map(|req| ready({
-            match req {
-                Ok(RegisterReply { request_id: 0, .. }) => None,
-                _ => Some(async {
-                    let req = req?;
-                    registration.send(RegisterRequest {
-                        req: Some(register_request::Req::ApproveId(req.request_id))
-                    }).await.map_err(|_| anyhow!("bruh moment2"))?;
-                    let ep = req.endpoint.unwrap();
-                    let addr = Ipv6Addr::from(<[u8; 16]>::try_from(&ep.addr[..])?);
-                    let port = ep.port.try_into()?;
-                    let ep = SocketAddrV6::new(addr, port, 0, 0);
-                    Ok::<_, anyhow::Error>((
-                        SecureTransport::connect(&endpoint, ep.into(), Side::Responder { local_private_key: &PRIVATE_KEY }).await?,
-                        ep
-                    ))
-                })
+        ).left_stream()
+    };
+
+    let listener = peers
+        .map(|peer| async {
+            let peer = peer?;
+            let request = policy::Request::new(Some(peer.endpoint()));
+            if let Err(e) = policy.check(&request).await {
+                println!("denied rendezvous from {request}: {e}");
+                peer.deny();
+                return Ok(None);
+            }
+            let transport = peer.accept().await?;
+            let ep = transport.peer_addr()?;
+            Ok::<_, anyhow::Error>(Some((transport, ep)))
+        })
+        .buffer_unordered(16);
+
+    // Direct connections come in on a port of their own: UDT would hand rendezvous
+    // handshakes to a listener on the mediator's port.
+    let direct = if listen_port.is_some() || lan {
+        let socket = rendezvous::bind_udt(listen_port.unwrap_or(0))?;
+        let port = socket.local_addr()?.port();
+        let listener = socket.listen_datagram(DIRECT_BACKLOG)?;
+        println!("accepting direct connections on port {port}");
+        if lan {
+            println!("announcing on the LAN");
+            tokio::spawn(async move {
+                if let Err(e) = rendezvous::lan::announce(NAME, &PUBLIC_KEY, port).await {
+                    println!("stopped announcing: {e}");
+                }
+            });
+        }
+        stream::unfold((listener, Duration::ZERO), |(listener, backoff)| async move {
+            if !backoff.is_zero() {
+                time::sleep(backoff).await;
+            }
+            let conn = listener.accept().await;
+            let backoff = match conn {
+                Ok(_) => Duration::ZERO,
+                Err(_) => (backoff * 2).clamp(MIN_ACCEPT_BACKOFF, MAX_ACCEPT_BACKOFF)
+            };
+            Some((conn, (listener, backoff)))
+        }).left_stream()
+    } else {
+        stream::empty().right_stream()
+    };
+    let direct = direct
+        .map(|conn| async {
+            let conn = conn?;
+            let request = policy::Request::direct(conn.peer_addr()?.ip());
+            if let Err(e) = policy.check(&request).await {
+                println!("denied connection from {request}: {e}");
+                return Ok(None);
             }
-        }))
+            let transport = SecureTransport::accept(conn, &PRIVATE_KEY).await?;
+            let ep = transport.peer_addr()?;
+            Ok::<_, anyhow::Error>(Some((transport, ep)))
+        })
         .buffer_unordered(16);
 
+    let listener = stream::select(listener, direct)
+        .filter_map(|res| ready(res.transpose()));
+
+    // Losing the registration is fatal, losing a connection isn't
     let listener = listener.filter(|e| ready(match e {
         Ok(_) => true,
         Err(e) => {
-            let is_tonic_error = e.chain()
-                .find_map(<dyn Error>::downcast_ref::<tonic::Status>)
-                .is_some();
-            if !is_tonic_error {
+            let is_fatal = e.is::<rendezvous::Error>();
+            if !is_fatal {
                 println!("connection failed: {e}");
             }
-            is_tonic_error
+            is_fatal
         }
     }));
 
     let (listener, _handle) = abortable(listener);
     // ctrlc::set_handler(move || handle.abort())?;
 
-    np::serve_mux(Arc::new(Handler::new([
-        ("forfun".into(), "forfun".into()),
-        ("ff2".into(), "forfun".into())
-    ].into_iter().collect())), pin!(listener)).await?;
+    np::serve_mux(Arc::new(Handler::new(shares)), pin!(listener)).await?;
     
     Ok(())
 }
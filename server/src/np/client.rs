@@ -115,7 +115,11 @@ async fn dispatch<S: Serve>(
 
             let mut resources = resource_mgr.resources.write().await;
 
-            if resources.contains_key(&newfid) {
+            // walk(5) lets newfid be fid itself, which moves fid to where the walk ends,
+            // and leaves it where it was if the walk falls short. Such walks used to be
+            // refused with "fid in use"; a reconnecting client makes them when it attaches
+            // each fid again under its own number and walks it back in place.
+            if newfid != fid && resources.contains_key(&newfid) {
                 return Err(rerror("fid in use"));
             }
             let resource = resources.get(&fid).ok_or_else(|| rerror("Fid not"))?;
@@ -0,0 +1,235 @@
+use std::{collections::HashMap, fmt::{self, Display, Write as _}, net::{IpAddr, Ipv6Addr}, process::Stdio, str::FromStr, sync::Mutex, time::{Duration, Instant}};
+
+use anyhow::{anyhow, bail, Context};
+use mediator_proto::{candidate, Endpoint};
+use tokio::{io::{self, AsyncBufReadExt, BufReader, Stdin}, process::Command, time};
+
+// How long the hook or the operator gets to make up their mind
+const DECISION_TIMEOUT: Duration = Duration::from_secs(30);
+// Shorter fingerprints would match keys by accident
+const MIN_FINGERPRINT_LEN: usize = 16;
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+// Sources the rate limiter keeps track of before it forgets the idle ones
+const MAX_TRACKED_SOURCES: usize = 4096;
+
+/// An address range, or a prefix of a key fingerprint (`key:<hex>`).
+#[derive(Debug, Clone)]
+enum Rule {
+    Net { addr: IpAddr, prefix: u32 },
+    Key(String)
+}
+
+fn bits(ip: IpAddr) -> (u128, u32) {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => (u32::from(ip).into(), 32),
+        IpAddr::V6(ip) => (ip.into(), 128)
+    }
+}
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(fp) = s.strip_prefix("key:") {
+            if fp.len() < MIN_FINGERPRINT_LEN || !fp.chars().all(|c| c.is_ascii_hexdigit()) {
+                bail!("bad key fingerprint {fp:?}");
+            }
+            return Ok(Self::Key(fp.to_ascii_lowercase()));
+        }
+        let (addr, prefix) = s.split_once('/').map_or((s, None), |(a, p)| (a, Some(p)));
+        let addr = addr.parse::<IpAddr>().with_context(|| format!("bad address {addr:?}"))?.to_canonical();
+        let len = bits(addr).1;
+        let prefix = match prefix {
+            Some(p) => p.parse().ok().filter(|&p| p <= len).ok_or_else(|| anyhow!("bad prefix length {p:?}"))?,
+            None => len
+        };
+        Ok(Self::Net { addr, prefix })
+    }
+}
+
+impl Rule {
+    fn matches(&self, req: &Request) -> bool {
+        match self {
+            // Only the source will do: the other addresses are the requester's word
+            Self::Net { addr, prefix } => req.source.is_some_and(|ip| {
+                let ((ip, ip_len), (net, net_len)) = (bits(ip), bits(*addr));
+                ip_len == net_len && (ip ^ net).checked_shr(net_len - prefix).unwrap_or(0) == 0
+            }),
+            Self::Key(fp) => req.fingerprint.as_deref().is_some_and(|k| k.starts_with(fp.as_str()))
+        }
+    }
+}
+
+/// A rendezvous request (or a direct connection), as far as the policy is concerned.
+///
+/// Everything in it is claimed by the requester, except for its reflexive address, which
+/// the mediator observed. In particular, nothing has checked the key yet.
+#[derive(Debug, Clone)]
+pub struct Request {
+    addrs: Vec<IpAddr>,
+    /// The reflexive address, if the mediator saw one worth handing out
+    source: Option<IpAddr>,
+    fingerprint: Option<String>
+}
+
+impl Request {
+    pub fn new(ep: Option<&Endpoint>) -> Self {
+        let candidates = ep.map(|ep| &ep.candidates[..]).unwrap_or_default();
+        let addrs = candidates.iter().filter_map(|c| c.ip()).collect::<Vec<_>>();
+        let source = candidates.iter()
+            .find(|c| c.kind() == candidate::Kind::Reflexive)
+            .and_then(|c| c.ip());
+        let fingerprint = ep.filter(|ep| !ep.pubkey.is_empty()).map(|ep| {
+            ep.pubkey.iter().fold(String::new(), |mut s, b| {
+                let _ = write!(s, "{b:02x}");
+                s
+            })
+        });
+        Self { addrs, source, fingerprint }
+    }
+
+    /// A peer connecting directly, rather than through the mediator. We only know its
+    /// address, which the connection vouches for.
+    pub fn direct(ip: IpAddr) -> Self {
+        let ip = ip.to_canonical();
+        Self { addrs: vec![ip], source: Some(ip), fingerprint: None }
+    }
+}
+
+impl Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.source {
+            Some(ip) => write!(f, "{ip}")?,
+            None => f.write_str("nowhere")?
+        }
+        if let Some(fp) = &self.fingerprint {
+            write!(f, " (key {fp})")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum Approver {
+    /// Runs a command, which approves by exiting successfully
+    Hook(String),
+    /// Asks on stdin, one request at a time
+    Prompt(tokio::sync::Mutex<BufReader<Stdin>>)
+}
+
+/// Decides which rendezvous requests to approve.
+///
+/// A request is denied if its source address or its key matches the denylist, or if
+/// the allowlist is non-empty and neither matches it. Then it's rate limited per source
+/// address, and finally handed to the hook or the operator, if there is one.
+#[derive(Debug)]
+pub struct Policy {
+    allow: Vec<Rule>,
+    deny: Vec<Rule>,
+    /// Per source, per minute
+    rate_limit: Option<u32>,
+    windows: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+    approver: Option<Approver>
+}
+
+fn rules(var: &str) -> anyhow::Result<Vec<Rule>> {
+    match std::env::var(var) {
+        Ok(list) => list.split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse().with_context(|| format!("in {var}")))
+            .collect(),
+        Err(_) => Ok(Vec::new())
+    }
+}
+
+impl Policy {
+    /// Reads the policy from the environment:
+    /// - `ALLOW` and `DENY`: comma-separated addresses, ranges (`10.0.0.0/8`) and key
+    ///   fingerprints (`key:<hex>`, at least 16 digits)
+    /// - `RATE_LIMIT`: requests per source per minute
+    /// - `APPROVE_HOOK`: a command to run for each request, with `NINEWIRE_SOURCE`,
+    ///   `NINEWIRE_ADDRS` and `NINEWIRE_KEY` set
+    /// - `APPROVE_PROMPT`: if set, asks on stdin instead
+    pub fn from_env() -> anyhow::Result<Self> {
+        let rate_limit = match std::env::var("RATE_LIMIT") {
+            Ok(limit) => Some(limit.parse().context("bad RATE_LIMIT")?),
+            Err(_) => None
+        };
+        let approver = match (std::env::var("APPROVE_HOOK"), std::env::var_os("APPROVE_PROMPT")) {
+            (Ok(_), Some(_)) => bail!("APPROVE_HOOK and APPROVE_PROMPT are mutually exclusive"),
+            (Ok(hook), None) => Some(Approver::Hook(hook)),
+            (Err(_), Some(_)) => Some(Approver::Prompt(tokio::sync::Mutex::new(BufReader::new(io::stdin())))),
+            (Err(_), None) => None
+        };
+        Ok(Self {
+            allow: rules("ALLOW")?,
+            deny: rules("DENY")?,
+            rate_limit,
+            windows: Mutex::default(),
+            approver
+        })
+    }
+
+    /// Returns why `req` is denied, if it is.
+    pub async fn check(&self, req: &Request) -> anyhow::Result<()> {
+        if self.deny.iter().any(|r| r.matches(req)) {
+            bail!("denylisted");
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|r| r.matches(req)) {
+            bail!("not allowlisted");
+        }
+        // Requests without a source share a budget
+        if !self.take(req.source.unwrap_or(Ipv6Addr::UNSPECIFIED.into())) {
+            bail!("rate limited");
+        }
+        match &self.approver {
+            None => Ok(()),
+            Some(Approver::Hook(hook)) => {
+                let mut cmd = Command::new(hook);
+                cmd.stdin(Stdio::null())
+                    .kill_on_drop(true)
+                    .env("NINEWIRE_SOURCE", req.source.map(|ip| ip.to_string()).unwrap_or_default())
+                    .env("NINEWIRE_ADDRS", req.addrs.iter().map(IpAddr::to_string).collect::<Vec<_>>().join(" "))
+                    .env("NINEWIRE_KEY", req.fingerprint.as_deref().unwrap_or_default());
+                let status = time::timeout(DECISION_TIMEOUT, cmd.status()).await
+                    .map_err(|_| anyhow!("hook timed out"))?
+                    .context("couldn't run hook")?;
+                if !status.success() {
+                    bail!("hook said no ({status})");
+                }
+                Ok(())
+            },
+            Some(Approver::Prompt(stdin)) => {
+                let mut stdin = stdin.lock().await;
+                println!("rendezvous from {req}, approve? [y/N]");
+                let mut line = String::new();
+                time::timeout(DECISION_TIMEOUT, stdin.read_line(&mut line)).await
+                    .map_err(|_| anyhow!("nobody answered"))??;
+                if !matches!(line.trim(), "y" | "Y" | "yes") {
+                    bail!("operator said no");
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Counts a request from `source`, unless it's over the limit.
+    fn take(&self, source: IpAddr) -> bool {
+        let Some(limit) = self.rate_limit else { return true };
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() >= MAX_TRACKED_SOURCES {
+            windows.retain(|_, (start, _)| now.duration_since(*start) < RATE_WINDOW);
+        }
+        let (start, count) = windows.entry(source.to_canonical()).or_insert((now, 0));
+        if now.duration_since(*start) >= RATE_WINDOW {
+            (*start, *count) = (now, 0);
+        }
+        if *count >= limit {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+}
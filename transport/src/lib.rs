@@ -1,11 +1,13 @@
 #![forbid(unsafe_code)]
 
-use std::{io, net::SocketAddr, ops::RangeInclusive, sync::{atomic::{AtomicU64, Ordering}, Arc}};
+use std::{io, net::SocketAddr, ops::RangeInclusive, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::Duration};
 
 use parking_lot::Mutex;
 use range_set::RangeSet;
+use futures::{stream::FuturesUnordered, StreamExt};
 use scc::Bag;
 use snow::StatelessTransportState;
+use tokio::time;
 use tracing::trace;
 use udt::{Connection, Endpoint};
 
@@ -22,6 +24,22 @@ pub struct SecureTransport {
     nonce_incoming: Mutex<RangeSet<[RangeInclusive<u64>; 1]>>
 }
 
+/// How long [`SecureTransport::connect_any`] waits before starting the next attempt
+pub const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// How long [`SecureTransport::connect_or_relay`] tries to connect directly
+pub const DIRECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long [`SecureTransport::connect_relay`] waits for the relay to pair us with the
+/// peer and for the handshake
+pub const RELAY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long [`SecureTransport::accept`] waits for the initiator to finish the handshake
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Reasonable yet lean buffer size for pure handshake messages
+const HANDSHAKE_BUF: usize = 64;
+
 #[derive(Debug, Clone, Copy)]
 pub enum Side<'a> {
     Initiator { remote_public_key: &'a [u8] },
@@ -32,7 +50,93 @@ impl SecureTransport {
     // Feature: add support for 0/0.5 RTT data (e.g. a fast Tversion/Rversion)
     // Of course, fast-open is a pipe dream considering the very nature of rendezvous sockets.
     pub async fn connect(ep: &Arc<Endpoint>, addr: SocketAddr, side: Side<'_>) -> io::Result<Self> {
-        let inner = ep.connect_datagram(addr, true).await?;
+        Self::connect_any(ep, &[addr], side).await
+    }
+
+    /// Rendezvous with the peer at whichever of `addrs` answers first, happy eyeballs
+    /// style: attempts start [`ATTEMPT_DELAY`] apart, in order, and run in parallel.
+    ///
+    /// Both sides may complete more than one rendezvous, so the initiator picks the first
+    /// one and starts the handshake on it, and the responder waits for the first one the
+    /// handshake arrives on. The rest are closed.
+    pub async fn connect_any(ep: &Arc<Endpoint>, addrs: &[SocketAddr], side: Side<'_>) -> io::Result<Self> {
+        Self::race(ep, addrs, side, true).await
+    }
+
+    /// Connects to a peer listening at whichever of `addrs` answers first, like
+    /// [`SecureTransport::connect_any`]. See [`SecureTransport::accept`] for the other end.
+    pub async fn dial(ep: &Arc<Endpoint>, addrs: &[SocketAddr], remote_public_key: &[u8]) -> io::Result<Self> {
+        Self::race(ep, addrs, Side::Initiator { remote_public_key }, false).await
+    }
+
+    /// Handshakes as the responder on a connection from [`udt::Listener::accept`].
+    pub async fn accept(inner: Connection, local_private_key: &[u8]) -> io::Result<Self> {
+        time::timeout(HANDSHAKE_TIMEOUT, Self::handshake(inner, Vec::new(), Side::Responder { local_private_key })).await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+    }
+
+    async fn race(ep: &Arc<Endpoint>, addrs: &[SocketAddr], side: Side<'_>, rendezvous: bool) -> io::Result<Self> {
+        let responder = matches!(side, Side::Responder { .. });
+        let mut attempts = addrs.iter().enumerate()
+            .map(|(i, &addr)| async move {
+                time::sleep(ATTEMPT_DELAY * i as u32).await;
+                let inner = ep.connect_datagram(addr, rendezvous).await?;
+                let mut first = Vec::new();
+                if responder {
+                    first.resize(HANDSHAKE_BUF, 0);
+                    let n = inner.recv(&mut first).await?;
+                    first.truncate(n);
+                }
+                Ok::<_, io::Error>((inner, first))
+            })
+            .collect::<FuturesUnordered<_>>();
+
+        let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to");
+        while let Some(res) = attempts.next().await {
+            match res {
+                Ok((inner, first)) => {
+                    drop(attempts);
+                    return Self::handshake(inner, first, side).await;
+                },
+                Err(e) => {
+                    trace!("connection attempt failed: {e}");
+                    last_err = e;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Connects through a relay, which pairs us with the peer presenting the same `token`.
+    /// The relay forwards our datagrams as they are, so it never sees plaintext.
+    /// Gives up after [`RELAY_TIMEOUT`].
+    pub async fn connect_relay(ep: &Arc<Endpoint>, relay: SocketAddr, token: &[u8], side: Side<'_>) -> io::Result<Self> {
+        let connect = async {
+            let inner = ep.connect_datagram(relay, false).await?;
+            inner.send(token).await?;
+            // The relay says when the peer has shown up, so nothing gets sent into the void.
+            let mut paired = [0; 1];
+            inner.recv(&mut paired).await?;
+            Self::handshake(inner, Vec::new(), side).await
+        };
+        time::timeout(RELAY_TIMEOUT, connect).await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+    }
+
+    /// Like [`SecureTransport::connect_any`], but if that hasn't succeeded within
+    /// [`DIRECT_TIMEOUT`], falls back to `relay` (its address and token), if there is one.
+    pub async fn connect_or_relay(ep: &Arc<Endpoint>, addrs: &[SocketAddr], relay: Option<(SocketAddr, &[u8])>, side: Side<'_>) -> io::Result<Self> {
+        let err = match time::timeout(DIRECT_TIMEOUT, Self::connect_any(ep, addrs, side)).await {
+            Ok(Ok(transport)) => return Ok(transport),
+            Ok(Err(e)) => e,
+            Err(_) => io::ErrorKind::TimedOut.into()
+        };
+        let Some((relay, token)) = relay else { return Err(err) };
+        trace!("direct connection failed ({err}), trying relay at {relay}");
+        Self::connect_relay(ep, relay, token, side).await
+    }
+
+    async fn handshake(inner: Connection, first: Vec<u8>, side: Side<'_>) -> io::Result<Self> {
         // TODO: negotiate AES for accelerated hosts, and ChaChaPoly otherwise
         let crypto = snow::Builder::new("Noise_NK_25519_AESGCM_SHA256".parse().unwrap());
         let mut crypto = match side {
@@ -44,8 +148,11 @@ impl SecureTransport {
                 .build_responder().unwrap()
         };
 
-        // Reasonable yet lean buffer size for pure handshake messages
-        let mut buf = [0; 64];
+        if !first.is_empty() {
+            crypto.read_message(&first, &mut []).map_err(io::Error::other)?;
+        }
+
+        let mut buf = [0; HANDSHAKE_BUF];
         while !crypto.is_handshake_finished() {
             if crypto.is_my_turn() {
                 let n = crypto.write_message(&[], &mut buf).map_err(io::Error::other)?;
@@ -73,6 +180,11 @@ impl SecureTransport {
         self.inner.peer_addr()
     }
 
+    /// Caps the sending rate in bytes per second. See [`udt::Connection::set_max_bandwidth`].
+    pub fn set_max_bandwidth(&self, bytes_per_sec: Option<u64>) -> io::Result<()> {
+        self.inner.set_max_bandwidth(bytes_per_sec)
+    }
+
     pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
         let mut tmp = self.buffers.pop().unwrap_or_default();
         let tgt = buf.len() + 8 + 16;
@@ -0,0 +1,90 @@
+/*****************************************************************************
+Congestion control implemented in Rust
+*****************************************************************************/
+
+#include "udt-sys/src/lib.rs.h"
+#include "ccc.h"
+#include "rustcc.h"
+
+namespace UDT {
+
+class RustCC : public CCC
+{
+friend int32_t cc_syn_interval(const RustCC&);
+friend int32_t cc_mss(const RustCC&);
+friend int32_t cc_rtt(const RustCC&);
+friend int32_t cc_bandwidth(const RustCC&);
+friend int32_t cc_rcv_rate(const RustCC&);
+friend int32_t cc_snd_curr_seqno(const RustCC&);
+friend double cc_max_cwnd_size(const RustCC&);
+friend double cc_pkt_snd_period(const RustCC&);
+friend void cc_set_pkt_snd_period(RustCC&, double);
+friend double cc_cwnd_size(const RustCC&);
+friend void cc_set_cwnd_size(RustCC&, double);
+friend void cc_set_ack_timer(RustCC&, int32_t);
+friend void cc_set_ack_interval(RustCC&, int32_t);
+friend void cc_set_rto(RustCC&, int32_t);
+
+public:
+    explicit RustCC(rust::Box<rustcc::CcBox> cc) : m_cc(std::move(cc)) {}
+
+public:
+    // CCC interface implementation
+    virtual void init() { m_cc->init(*this); }
+    virtual void close() { m_cc->close(*this); }
+    virtual void onACK(int32_t ackno) { m_cc->on_ack(*this, ackno); }
+    virtual void onLoss(const int32_t* losslist, int size)
+    {
+        m_cc->on_loss(*this, rust::Slice<const int32_t>(losslist, size));
+    }
+    virtual void onTimeout() { m_cc->on_timeout(*this); }
+    virtual void onPktSent(const CPacket* pkt)
+    {
+        m_cc->on_pkt_sent(*this, pkt->m_iSeqNo, pkt->getLength(), pkt->m_iTimeStamp);
+    }
+    virtual void onPktReceived(const CPacket* pkt)
+    {
+        m_cc->on_pkt_received(*this, pkt->m_iSeqNo, pkt->getLength(), pkt->m_iTimeStamp);
+    }
+
+private:
+    rust::Box<rustcc::CcBox> m_cc;
+};
+
+class RustCCFactory : public CCCVirtualFactory
+{
+public:
+    explicit RustCCFactory(rust::Box<rustcc::CcFactory> factory) : m_factory(std::move(factory)) {}
+
+    virtual std::unique_ptr<CCC> create() { return std::make_unique<RustCC>(m_factory->create()); }
+    virtual std::unique_ptr<CCCVirtualFactory> clone() { return std::make_unique<RustCCFactory>(m_factory->clone_box()); }
+
+private:
+    rust::Box<rustcc::CcFactory> m_factory;
+};
+
+int32_t cc_syn_interval(const RustCC& cc) { return cc.m_iSYNInterval; }
+int32_t cc_mss(const RustCC& cc) { return cc.m_iMSS; }
+int32_t cc_rtt(const RustCC& cc) { return cc.m_iRTT; }
+int32_t cc_bandwidth(const RustCC& cc) { return cc.m_iBandwidth; }
+int32_t cc_rcv_rate(const RustCC& cc) { return cc.m_iRcvRate; }
+int32_t cc_snd_curr_seqno(const RustCC& cc) { return cc.m_iSndCurrSeqNo; }
+double cc_max_cwnd_size(const RustCC& cc) { return cc.m_dMaxCWndSize; }
+
+double cc_pkt_snd_period(const RustCC& cc) { return cc.m_dPktSndPeriod; }
+void cc_set_pkt_snd_period(RustCC& cc, double period) { cc.m_dPktSndPeriod = period; }
+double cc_cwnd_size(const RustCC& cc) { return cc.m_dCWndSize; }
+void cc_set_cwnd_size(RustCC& cc, double cwnd) { cc.m_dCWndSize = cwnd; }
+
+void cc_set_ack_timer(RustCC& cc, int32_t ms) { cc.setACKTimer(ms); }
+void cc_set_ack_interval(RustCC& cc, int32_t pkts) { cc.setACKInterval(pkts); }
+void cc_set_rto(RustCC& cc, int32_t us) { cc.setRTO(us); }
+
+int set_rust_cc(UDTSOCKET u, rust::Box<rustcc::CcFactory> factory)
+{
+    // UDT clones the factory, so this one can live on the stack
+    RustCCFactory f(std::move(factory));
+    return UDT::setsockopt(u, 0, UDT_CC, &f, sizeof(CCCVirtualFactory*));
+}
+
+}
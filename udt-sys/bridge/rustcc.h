@@ -0,0 +1,46 @@
+/*****************************************************************************
+Congestion control implemented in Rust
+
+RustCC forwards every CCC callback to a boxed Rust object (udt_sys::cc),
+and exposes the protected CCC state back to it through the accessors below.
+
+The class itself lives in rustcc.cpp, since ccc.h needs CPerfMon, which is
+only defined by the generated bridge header that includes this one.
+*****************************************************************************/
+
+#ifndef __RUSTCC_H__
+#define __RUSTCC_H__
+
+#include <cstdint>
+#include "rust/cxx.h"
+#include "udt.h"
+
+namespace rustcc { struct CcBox; struct CcFactory; }
+
+namespace UDT {
+
+class RustCC;
+
+int32_t cc_syn_interval(const RustCC& cc);
+int32_t cc_mss(const RustCC& cc);
+int32_t cc_rtt(const RustCC& cc);
+int32_t cc_bandwidth(const RustCC& cc);
+int32_t cc_rcv_rate(const RustCC& cc);
+int32_t cc_snd_curr_seqno(const RustCC& cc);
+double cc_max_cwnd_size(const RustCC& cc);
+
+double cc_pkt_snd_period(const RustCC& cc);
+void cc_set_pkt_snd_period(RustCC& cc, double period);
+double cc_cwnd_size(const RustCC& cc);
+void cc_set_cwnd_size(RustCC& cc, double cwnd);
+
+void cc_set_ack_timer(RustCC& cc, int32_t ms);
+void cc_set_ack_interval(RustCC& cc, int32_t pkts);
+void cc_set_rto(RustCC& cc, int32_t us);
+
+// Equivalent to setsockopt(u, 0, UDT_CC, ...) with a factory for RustCC
+int set_rust_cc(UDTSOCKET u, rust::Box<rustcc::CcFactory> factory);
+
+}
+
+#endif // __RUSTCC_H__
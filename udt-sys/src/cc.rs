@@ -0,0 +1,202 @@
+use std::{fmt::{self, Debug}, pin::Pin, sync::Arc};
+
+use super::*;
+
+/// A congestion control algorithm, mirroring UDT's `CCC` callbacks.
+///
+/// Every callback runs on UDT's internal threads with the connection locked, so they
+/// should be quick. A panic aborts the process.
+#[allow(unused_variables)]
+pub trait CongestionControl: Send {
+    /// Called once the connection is set up.
+    fn init(&mut self, ctl: &mut Control<'_>) {}
+    /// Called when the connection is closed.
+    fn close(&mut self, ctl: &mut Control<'_>) {}
+    /// Called when an ACK arrives. `ackno` is the sequence number it acknowledges.
+    fn on_ack(&mut self, ctl: &mut Control<'_>, ackno: i32) {}
+    /// Called when a loss report (NAK) arrives.
+    fn on_loss(&mut self, ctl: &mut Control<'_>, losses: LossList<'_>) {}
+    /// Called when nothing has been acknowledged for a retransmission timeout.
+    fn on_timeout(&mut self, ctl: &mut Control<'_>) {}
+    /// Called after a data packet is sent.
+    fn on_pkt_sent(&mut self, ctl: &mut Control<'_>, pkt: PacketInfo) {}
+    /// Called after a data packet is received.
+    fn on_pkt_received(&mut self, ctl: &mut Control<'_>, pkt: PacketInfo) {}
+}
+
+/// The connection state visible to a [`CongestionControl`].
+///
+/// The sending period and congestion window are read back by UDT after every callback.
+pub struct Control<'a>(Pin<&'a mut RustCC>);
+
+impl Control<'_> {
+    /// UDT's SYN interval (the rate control period), in microseconds.
+    pub fn syn_interval(&self) -> i32 {
+        cc_syn_interval(&self.0)
+    }
+
+    /// Maximum packet size in bytes, including all headers.
+    pub fn mss(&self) -> i32 {
+        cc_mss(&self.0)
+    }
+
+    /// Smoothed round-trip time, in microseconds.
+    pub fn rtt(&self) -> i32 {
+        cc_rtt(&self.0)
+    }
+
+    /// Estimated link capacity, in packets per second.
+    pub fn bandwidth(&self) -> i32 {
+        cc_bandwidth(&self.0)
+    }
+
+    /// Packet arrival rate at the receiver, in packets per second.
+    pub fn rcv_rate(&self) -> i32 {
+        cc_rcv_rate(&self.0)
+    }
+
+    /// Largest sequence number sent so far.
+    pub fn snd_curr_seqno(&self) -> i32 {
+        cc_snd_curr_seqno(&self.0)
+    }
+
+    /// Upper bound of the congestion window (the flow window), in packets.
+    pub fn max_cwnd_size(&self) -> f64 {
+        cc_max_cwnd_size(&self.0)
+    }
+
+    /// Interval between packets, in microseconds.
+    pub fn pkt_snd_period(&self) -> f64 {
+        cc_pkt_snd_period(&self.0)
+    }
+
+    pub fn set_pkt_snd_period(&mut self, us: f64) {
+        cc_set_pkt_snd_period(self.0.as_mut(), us);
+    }
+
+    /// Congestion window, in packets.
+    pub fn cwnd_size(&self) -> f64 {
+        cc_cwnd_size(&self.0)
+    }
+
+    pub fn set_cwnd_size(&mut self, pkts: f64) {
+        cc_set_cwnd_size(self.0.as_mut(), pkts);
+    }
+
+    /// Sends an ACK every `ms` milliseconds (capped at the SYN interval).
+    pub fn set_ack_timer(&mut self, ms: i32) {
+        cc_set_ack_timer(self.0.as_mut(), ms);
+    }
+
+    /// Sends an ACK every `pkts` packets.
+    pub fn set_ack_interval(&mut self, pkts: i32) {
+        cc_set_ack_interval(self.0.as_mut(), pkts);
+    }
+
+    /// Overrides the retransmission timeout, in microseconds.
+    pub fn set_rto(&mut self, us: i32) {
+        cc_set_rto(self.0.as_mut(), us);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketInfo {
+    pub seqno: i32,
+    /// Payload size in bytes
+    pub len: i32,
+    /// Microseconds since the connection started, per the sender's clock
+    pub timestamp: i32
+}
+
+/// A NAK's loss list.
+///
+/// On the wire, a lone sequence number is a single loss, and a number with the high bit
+/// set starts a range whose (inclusive) end is the next number.
+#[derive(Debug, Clone, Copy)]
+pub struct LossList<'a>(&'a [i32]);
+
+impl<'a> LossList<'a> {
+    pub fn as_raw(&self) -> &'a [i32] {
+        self.0
+    }
+
+    /// The lost sequence numbers as inclusive ranges.
+    pub fn ranges(&self) -> impl Iterator<Item = (i32, i32)> + 'a {
+        let mut raw = self.0.iter().copied();
+        std::iter::from_fn(move || {
+            let first = raw.next()?;
+            if first < 0 {
+                let first = first & 0x7fff_ffff;
+                Some((first, raw.next().unwrap_or(first)))
+            } else {
+                Some((first, first))
+            }
+        })
+    }
+}
+
+type MakeCc = dyn Fn() -> Box<dyn CongestionControl> + Send + Sync;
+
+/// Creates a [`CongestionControl`] for each connection it's attached to.
+#[derive(Clone)]
+pub struct CcFactory(Arc<MakeCc>);
+
+impl CcFactory {
+    pub fn new<C: CongestionControl + 'static>(make: impl Fn() -> C + Send + Sync + 'static) -> Self {
+        Self(Arc::new(move || Box::new(make())))
+    }
+
+    /// Attaches this algorithm to `u`, which must not be connecting or connected yet.
+    ///
+    /// # Safety
+    /// Same as for [`setsockopt`].
+    pub unsafe fn attach(&self, u: Socket) -> i32 {
+        unsafe { set_rust_cc(u, Box::new(self.clone())) }
+    }
+
+    pub(crate) fn create(&self) -> Box<CcBox> {
+        Box::new(CcBox((self.0)()))
+    }
+
+    pub(crate) fn clone_box(&self) -> Box<CcFactory> {
+        Box::new(self.clone())
+    }
+}
+
+impl Debug for CcFactory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CcFactory").finish_non_exhaustive()
+    }
+}
+
+pub struct CcBox(Box<dyn CongestionControl>);
+
+impl CcBox {
+    pub(crate) fn init(&mut self, ctl: Pin<&mut RustCC>) {
+        self.0.init(&mut Control(ctl));
+    }
+
+    pub(crate) fn close(&mut self, ctl: Pin<&mut RustCC>) {
+        self.0.close(&mut Control(ctl));
+    }
+
+    pub(crate) fn on_ack(&mut self, ctl: Pin<&mut RustCC>, ackno: i32) {
+        self.0.on_ack(&mut Control(ctl), ackno);
+    }
+
+    pub(crate) fn on_loss(&mut self, ctl: Pin<&mut RustCC>, losslist: &[i32]) {
+        self.0.on_loss(&mut Control(ctl), LossList(losslist));
+    }
+
+    pub(crate) fn on_timeout(&mut self, ctl: Pin<&mut RustCC>) {
+        self.0.on_timeout(&mut Control(ctl));
+    }
+
+    pub(crate) fn on_pkt_sent(&mut self, ctl: Pin<&mut RustCC>, seqno: i32, len: i32, timestamp: i32) {
+        self.0.on_pkt_sent(&mut Control(ctl), PacketInfo { seqno, len, timestamp });
+    }
+
+    pub(crate) fn on_pkt_received(&mut self, ctl: Pin<&mut RustCC>, seqno: i32, len: i32, timestamp: i32) {
+        self.0.on_pkt_received(&mut Control(ctl), PacketInfo { seqno, len, timestamp });
+    }
+}
@@ -1,10 +1,12 @@
 mod rpoll;
+pub mod cc;
 // mod list;
 
 use std::os::raw::c_int;
 
 use cxx::CxxString;
 pub use rpoll::*;
+use cc::{CcBox, CcFactory};
 
 #[repr(transparent)]
 #[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
@@ -98,6 +100,8 @@ mod ffi {
         UDT_SNDDATA,
         #[rust_name = "RecvData"]
         UDT_RCVDATA,
+        #[rust_name = "V6Only"]
+        UDT_V6ONLY,
     }
 
     #[repr(u32)]
@@ -173,9 +177,27 @@ mod ffi {
         fn compute_md5(s: &CxxString, digest: &mut [u8; 16]);
     }
 
+    #[namespace = "rustcc"]
+    extern "Rust" {
+        type CcFactory;
+        type CcBox;
+
+        fn create(self: &CcFactory) -> Box<CcBox>;
+        fn clone_box(self: &CcFactory) -> Box<CcFactory>;
+
+        fn init(self: &mut CcBox, ctl: Pin<&mut RustCC>);
+        fn close(self: &mut CcBox, ctl: Pin<&mut RustCC>);
+        fn on_ack(self: &mut CcBox, ctl: Pin<&mut RustCC>, ackno: i32);
+        fn on_loss(self: &mut CcBox, ctl: Pin<&mut RustCC>, losslist: &[i32]);
+        fn on_timeout(self: &mut CcBox, ctl: Pin<&mut RustCC>);
+        fn on_pkt_sent(self: &mut CcBox, ctl: Pin<&mut RustCC>, seqno: i32, len: i32, timestamp: i32);
+        fn on_pkt_received(self: &mut CcBox, ctl: Pin<&mut RustCC>, seqno: i32, len: i32, timestamp: i32);
+    }
+
     extern "C++" {
         include!("udt.h");
         include!("bridge.h");
+        include!("rustcc.h");
 
         #[namespace = ""]
         type sockaddr;
@@ -211,11 +233,30 @@ mod ffi {
         unsafe fn getlasterror_code() -> i32;
         unsafe fn getsockstate(u: Socket) -> Status;
         unsafe fn perfmon(u: Socket, perf: &mut CPerfMon, clear: bool) -> i32;
+
+        unsafe fn set_rust_cc(u: Socket, factory: Box<CcFactory>) -> i32;
     }
 
     unsafe extern "C++" {
         unsafe fn getlasterror_desc<'a>() -> &'a CxxString;
         unsafe fn getrpoll<'a>() -> &'a RPoll;
+
+        type RustCC;
+
+        fn cc_syn_interval(cc: &RustCC) -> i32;
+        fn cc_mss(cc: &RustCC) -> i32;
+        fn cc_rtt(cc: &RustCC) -> i32;
+        fn cc_bandwidth(cc: &RustCC) -> i32;
+        fn cc_rcv_rate(cc: &RustCC) -> i32;
+        fn cc_snd_curr_seqno(cc: &RustCC) -> i32;
+        fn cc_max_cwnd_size(cc: &RustCC) -> f64;
+        fn cc_pkt_snd_period(cc: &RustCC) -> f64;
+        fn cc_set_pkt_snd_period(cc: Pin<&mut RustCC>, period: f64);
+        fn cc_cwnd_size(cc: &RustCC) -> f64;
+        fn cc_set_cwnd_size(cc: Pin<&mut RustCC>, cwnd: f64);
+        fn cc_set_ack_timer(cc: Pin<&mut RustCC>, ms: i32);
+        fn cc_set_ack_interval(cc: Pin<&mut RustCC>, pkts: i32);
+        fn cc_set_rto(cc: Pin<&mut RustCC>, us: i32);
     }
 }
 
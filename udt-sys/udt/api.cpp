@@ -400,6 +400,10 @@ UDTSTATUS CUDTUnited::getStatus(const UDTSOCKET u)
    if (i->second->m_pUDT->m_bBroken)
       return BROKEN;
 
+   // an asynchronous connect that timed out is left in CONNECTING by the rendezvous queue
+   if ((CONNECTING == i->second->m_Status) && !i->second->m_pUDT->m_bConnecting && !i->second->m_pUDT->m_bConnected)
+      return BROKEN;
+
    return i->second->m_Status;
 }
 
@@ -957,7 +961,7 @@ void CUDTUnited::updateMux(CUDTSocket* s, const sockaddr* addr, const UDPSOCKET*
       // find a reusable address
       for (auto& i : m_mMultiplexer)
       {
-         if ((i.second.m_iIPversion == s->m_pUDT->m_iIPversion) && (i.second.m_iMSS == s->m_pUDT->m_iMSS) && i.second.m_bReusable)
+         if ((i.second.m_iIPversion == s->m_pUDT->m_iIPversion) && (i.second.m_iMSS == s->m_pUDT->m_iMSS) && (i.second.m_bV6Only == s->m_pUDT->m_bV6Only) && i.second.m_bReusable)
          {
             if (i.second.m_iPort == port)
             {
@@ -978,11 +982,13 @@ void CUDTUnited::updateMux(CUDTSocket* s, const sockaddr* addr, const UDPSOCKET*
    m.m_iIPversion = s->m_pUDT->m_iIPversion;
    m.m_iRefCount = 1;
    m.m_bReusable = s->m_pUDT->m_bReuseAddr;
+   m.m_bV6Only = s->m_pUDT->m_bV6Only;
    m.m_iID = s->m_SocketID;
 
    m.m_pChannel = new CChannel(s->m_pUDT->m_iIPversion);
    m.m_pChannel->setSndBufSize(s->m_pUDT->m_iUDPSndBufSize);
    m.m_pChannel->setRcvBufSize(s->m_pUDT->m_iUDPRcvBufSize);
+   m.m_pChannel->setV6Only(s->m_pUDT->m_bV6Only);
 
    try
    {
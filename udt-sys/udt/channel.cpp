@@ -70,7 +70,8 @@ m_iIPversion(AF_INET),
 m_iSockAddrSize(sizeof(sockaddr_in)),
 m_iSocket(),
 m_iSndBufSize(65536),
-m_iRcvBufSize(65536)
+m_iRcvBufSize(65536),
+m_bV6Only(true)
 {
 }
 
@@ -78,7 +79,8 @@ CChannel::CChannel(int version):
 m_iIPversion(version),
 m_iSocket(),
 m_iSndBufSize(65536),
-m_iRcvBufSize(65536)
+m_iRcvBufSize(65536),
+m_bV6Only(true)
 {
    m_iSockAddrSize = (AF_INET == m_iIPversion) ? sizeof(sockaddr_in) : sizeof(sockaddr_in6);
 }
@@ -99,13 +101,13 @@ void CChannel::open(const sockaddr* addr)
    #endif
       throw CUDTException(1, 0, NET_ERROR);
 
-   // r: always enable v6only as a best practice and to improve compatibility
+   // r: enable v6only by default as a best practice and to improve compatibility
    if (m_iIPversion == AF_INET6) {
 #ifdef WINDOWS
-      DWORD v6only = 1;
+      DWORD v6only = m_bV6Only;
       const char *p_v6only = (const char *)&v6only;
 #else
-      int v6only = 1;
+      int v6only = m_bV6Only;
       const void *p_v6only = (const void *)&v6only;
 #endif
       if (0 != ::setsockopt(m_iSocket, IPPROTO_IPV6, IPV6_V6ONLY, p_v6only, sizeof v6only))
@@ -225,6 +227,11 @@ void CChannel::setRcvBufSize(int size)
    m_iRcvBufSize = size;
 }
 
+void CChannel::setV6Only(bool v6only)
+{
+   m_bV6Only = v6only;
+}
+
 void CChannel::getSockAddr(sockaddr* addr) const
 {
    socklen_t namelen = m_iSockAddrSize;
@@ -116,6 +116,15 @@ public:
 
    void setRcvBufSize(int size);
 
+      // Functionality:
+      //    Set whether an IPv6 channel refuses IPv4-mapped traffic.
+      // Parameters:
+      //    0) [in] v6only: value of IPV6_V6ONLY.
+      // Returned value:
+      //    None.
+
+   void setV6Only(bool v6only);
+
       // Functionality:
       //    Query the socket address that the channel is using.
       // Parameters:
@@ -165,6 +174,7 @@ private:
 
    int m_iSndBufSize;                   // UDP sending buffer size
    int m_iRcvBufSize;                   // UDP receiving buffer size
+   bool m_bV6Only;                      // IPV6_V6ONLY, for IPv6 channels
 };
 
 
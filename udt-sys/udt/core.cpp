@@ -113,6 +113,7 @@ CUDT::CUDT()
    m_iIPversion = AF_INET;
    m_bRendezvous = false;
    m_bReuseAddr = true;
+   m_bV6Only = true;
    m_llMaxBW = -1;
 
    m_pCCFactory = std::make_unique<CCCFactory<CUDTCC>>();
@@ -161,6 +162,7 @@ CUDT::CUDT(const CUDT& ancestor)
    m_iIPversion = ancestor.m_iIPversion;
    m_bRendezvous = ancestor.m_bRendezvous;
    m_bReuseAddr = true;	// this must be true, because all accepted sockets shared the same port with the listener
+   m_bV6Only = ancestor.m_bV6Only;
    m_llMaxBW = ancestor.m_llMaxBW;
 
    m_pCCFactory = ancestor.m_pCCFactory->clone();
@@ -307,6 +309,12 @@ void CUDT::setOpt(UDTOpt optName, const void* optval, int)
       m_bReuseAddr = *(bool*)optval;
       break;
 
+   case UDT_V6ONLY:
+      if (m_bOpened)
+         throw CUDTException(5, 1, 0);
+      m_bV6Only = *(bool*)optval;
+      break;
+
    case UDT_MAXBW:
       m_llMaxBW = *(int64_t*)optval;
       break;
@@ -375,6 +383,11 @@ void CUDT::getOpt(UDTOpt optName, void* optval, int& optlen)
       optlen = sizeof(bool);
       break;
 
+   case UDT_V6ONLY:
+      *(bool *)optval = m_bV6Only;
+      optlen = sizeof(bool);
+      break;
+
    case UDT_MAXBW:
       *(int64_t*)optval = m_llMaxBW;
       optlen = sizeof(int64_t);
@@ -728,8 +741,9 @@ POST_CONNECT:
    m_dCongestionWindow = m_pCC->m_dCWndSize;
 
    // And, I am connected too.
-   m_bConnecting = false;
+   // (m_bConnected goes first so getStatus never sees neither flag set mid-handshake)
    m_bConnected = true;
+   m_bConnecting = false;
 
    // register this socket for receiving data packets
    m_pRNode->m_bOnList = true;
@@ -882,8 +896,10 @@ void CUDT::close()
       m_bListening = false;
       m_pRcvQueue->removeListener(this);
    }
-   else if (m_bConnecting)
+   else if (!m_bConnected)
    {
+      // an asynchronous connect that timed out has already cleared m_bConnecting,
+      // but it's still registered with the rendezvous queue
       m_pRcvQueue->removeConnector(m_SocketID);
    }
 
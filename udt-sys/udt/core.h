@@ -236,6 +236,7 @@ private: // Options
    int m_iIPversion;                            // IP version
    bool m_bRendezvous;                          // Rendezvous connection mode
    bool m_bReuseAddr;				// reuse an exiting port or not, for UDP multiplexer
+   bool m_bV6Only;				// refuse IPv4-mapped traffic on an IPv6 UDP socket
    int64_t m_llMaxBW;				// maximum data transfer rate (threshold)
 
 private: // congestion control
@@ -511,6 +511,7 @@ struct CMultiplexer
    int m_iMSS;			// Maximum Segment Size
    int m_iRefCount;		// number of UDT instances that are associated with this multiplexer
    bool m_bReusable;		// if this one can be shared with others
+   bool m_bV6Only;		// IPV6_V6ONLY of the UDP socket
 
    int m_iID;			// multiplexer ID
 };
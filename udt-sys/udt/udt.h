@@ -114,7 +114,8 @@ enum UDTOpt
    UDT_STATE,		// current socket state, see UDTSTATUS, read only
    UDT_EVENT,		// current avalable events associated with the socket
    UDT_SNDDATA,		// size of data in the sending buffer
-   UDT_RCVDATA		// size of data available for recv
+   UDT_RCVDATA,		// size of data available for recv
+   UDT_V6ONLY		// r: IPV6_V6ONLY on the UDP socket (IPv6 only, default true)
 };
 
 ////////////////////////////////////////////////////////////////////////////////
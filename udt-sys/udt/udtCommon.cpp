@@ -523,7 +523,11 @@ void CIPAddress::ntop(const sockaddr* addr, uint32_t ip[4], int ver)
    if (AF_INET == ver)
    {
       sockaddr_in* a = (sockaddr_in*)addr;
-      ip[0] = a->sin_addr.s_addr;
+      // r: write it as IPv4-mapped, so that a dual-stack IPv6 peer reads the same address
+      ip[0] = 0;
+      ip[1] = 0;
+      ip[2] = 0xFFFF0000;
+      ip[3] = a->sin_addr.s_addr;
    }
    else
    {
@@ -540,7 +544,12 @@ void CIPAddress::pton(sockaddr* addr, const uint32_t ip[4], int ver)
    if (AF_INET == ver)
    {
       sockaddr_in* a = (sockaddr_in*)addr;
-      a->sin_addr.s_addr = ip[0];
+      // r: an IPv4-mapped address (from ntop above, or a dual-stack IPv6 peer) keeps the
+      // IPv4 address in the last word; anything else is an old-style 4-byte address
+      if ((0 == ip[0]) && (0 == ip[1]) && (0xFFFF0000 == ip[2]))
+         a->sin_addr.s_addr = ip[3];
+      else
+         a->sin_addr.s_addr = ip[0];
    }
    else
    {
@@ -1,13 +1,16 @@
 mod instance;
+mod options;
 mod util;
-use std::{io, mem, net::SocketAddr, ptr, sync::Arc, time::Duration};
+use std::{io, mem, net::SocketAddr, ptr, time::Duration};
 
 use futures::FutureExt;
 use instance::*;
-use tokio::task::spawn_blocking;
+pub use options::SocketOptions;
+pub use udt_sys::cc;
+use tokio::time;
 use util::*;
 use os_socketaddr::OsSocketAddr;
-use udt_sys::{INVALID_SOCK};
+use udt_sys::{Event, Status, INVALID_SOCK};
 
 cfg_if::cfg_if! {
     if #[cfg(windows)] {
@@ -17,6 +20,10 @@ cfg_if::cfg_if! {
     }
 }
 
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
 #[derive(Debug)]
 struct Socket {
     _inst: Instance,
@@ -44,12 +51,13 @@ impl Socket {
         Ok(addr)
     }
 
+    // Dual-stack sockets see IPv4 addresses as IPv4-mapped ones.
     fn local_addr(&self) -> io::Result<SocketAddr> {
-        self.local_addr_os().map(|addr| addr.into_addr().unwrap())
+        self.local_addr_os().map(|addr| canonical(addr.into_addr().unwrap()))
     }
 
     fn peer_addr(&self) -> io::Result<SocketAddr> {
-        self.peer_addr_os().map(|addr| addr.into_addr().unwrap())
+        self.peer_addr_os().map(|addr| canonical(addr.into_addr().unwrap()))
     }
 
     fn readable(&self) -> impl Future<Output = io::Result<()>> {
@@ -62,6 +70,29 @@ impl Socket {
         rpoll.writable(self.inner).unwrap().map(|_| Ok(()))
     }
 
+    fn try_connected(&self) -> io::Result<bool> {
+        let state = unsafe { udt_sys::getsockstate(self.inner) };
+        if state == Status::Connected {
+            Ok(true)
+        } else if state == Status::Connecting {
+            Ok(false)
+        } else if state == Status::Broken {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "Connection setup failure: connection time out."))
+        } else {
+            Err(io::ErrorKind::NotConnected.into())
+        }
+    }
+
+    async fn connected(&self) -> io::Result<()> {
+        loop {
+            if self.try_connected()? { break; }
+            let writable = self.writable();
+            if self.try_connected()? { break; }
+            writable.await?;
+        }
+        Ok(())
+    }
+
     fn send_data(&self) -> u32 {
         let mut inflight = 0;
         let mut _optlen = 0;
@@ -84,7 +115,8 @@ impl Drop for Socket {
 
 #[derive(Debug)]
 pub struct Endpoint {
-    binding: Socket
+    binding: Socket,
+    options: SocketOptions
 }
 
 #[derive(Debug)]
@@ -99,6 +131,12 @@ pub struct Connection {
 
 impl Endpoint {
     pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        Self::bind_with_options(addr, SocketOptions::default())
+    }
+
+    /// Binds with `options`, which also become the defaults for every socket listened
+    /// or connected on this endpoint.
+    pub fn bind_with_options(addr: SocketAddr, options: SocketOptions) -> io::Result<Self> {
         let inst = Instance::default();
         let binding = unsafe { udt_sys::socket(
             match addr {
@@ -109,6 +147,10 @@ impl Endpoint {
         if binding == INVALID_SOCK {
             return Err(unsafe { udt_getlasterror() });
         }
+        if let Err(e) = options.apply(binding) {
+            unsafe { udt_sys::close(binding) };
+            return Err(e);
+        }
         let addr = OsSocketAddr::from(addr);
         let res = unsafe { udt_sys::bind(
             binding,
@@ -120,7 +162,8 @@ impl Endpoint {
             return Err(unsafe { udt_getlasterror() });
         }
         Ok(Self {
-            binding: Socket { _inst: inst, inner: binding }
+            binding: Socket { _inst: inst, inner: binding },
+            options
         })
     }
 
@@ -128,6 +171,10 @@ impl Endpoint {
         self.binding.local_addr()
     }
 
+    pub fn options(&self) -> &SocketOptions {
+        &self.options
+    }
+
     fn listen(&self, type_: i32, backlog: u32) -> io::Result<Socket> {
         let inst = Instance::default();
         let addr = self.binding.local_addr_os()?;
@@ -140,6 +187,10 @@ impl Endpoint {
         if u == INVALID_SOCK {
             return Err(unsafe { udt_getlasterror() });
         }
+        if let Err(e) = self.options.apply(u) {
+            unsafe { udt_sys::close(u) };
+            return Err(e);
+        }
         let res = unsafe { udt_sys::bind(
             u,
             addr.as_ptr().cast(),
@@ -157,9 +208,27 @@ impl Endpoint {
         Ok(Socket { _inst: inst, inner: u})
     }
 
-    fn connect(&self, type_: i32, addr: SocketAddr, rendezvous: bool) -> io::Result<Socket> {
+    /// UDT wants the peer's address in the family of the local socket.
+    fn peer_addr_for(&self, local_addr: SocketAddr, addr: SocketAddr) -> io::Result<SocketAddr> {
+        match (local_addr, addr) {
+            (SocketAddr::V4(_), SocketAddr::V4(_)) | (SocketAddr::V6(_), SocketAddr::V6(_)) => Ok(addr),
+            (SocketAddr::V4(_), SocketAddr::V6(v6)) => match v6.ip().to_ipv4_mapped() {
+                Some(ip) => Ok(SocketAddr::new(ip.into(), v6.port())),
+                None => Err(io::Error::new(io::ErrorKind::InvalidInput, "can't reach an IPv6 address from an IPv4 endpoint"))
+            },
+            (SocketAddr::V6(_), SocketAddr::V4(v4)) => {
+                if unsafe { udt_getsockopt::<bool>(self.binding.inner, udt_sys::SocketOption::V6Only) }? {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "can't reach an IPv4 address from an IPv6-only endpoint"));
+                }
+                Ok(SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()))
+            }
+        }
+    }
+
+    fn connect(&self, type_: i32, addr: SocketAddr, rendezvous: bool, options: SocketOptions) -> io::Result<Socket> {
         let inst = Instance::default();
         let local_addr = self.binding.local_addr_os()?;
+        let addr = self.peer_addr_for(local_addr.into_addr().unwrap(), addr)?;
         let u = unsafe { udt_sys::socket(
             match local_addr.into_addr().unwrap() {
                 SocketAddr::V4(_) => AF_INET,
@@ -169,6 +238,10 @@ impl Endpoint {
         if u == INVALID_SOCK {
             return Err(unsafe { udt_getlasterror() });
         }
+        if let Err(e) = self.options.overlay(&options).apply(u) {
+            unsafe { udt_sys::close(u) };
+            return Err(e);
+        }
         let res = unsafe { udt_sys::bind(
             u,
             local_addr.as_ptr().cast(),
@@ -190,6 +263,21 @@ impl Endpoint {
                 return Err(unsafe { udt_getlasterror() });
             }
         }
+        // Don't block in connect; completion (or failure) is signalled through RPoll.
+        let syn = false;
+        let res = unsafe { udt_sys::setsockopt(
+            u, 0,
+            udt_sys::SocketOption::ConnSyn,
+            (&syn as *const bool).cast(),
+            mem::size_of::<bool>() as i32
+        ) };
+        if res == -1 {
+            unsafe { udt_sys::close(u) };
+            return Err(unsafe { udt_getlasterror() });
+        }
+        // UDT doesn't register the socket with RPoll until the handshake is done, so do it
+        // ourselves before anyone waits on it.
+        unsafe { udt_sys::getrpoll() }.update_events(u, Event::empty(), false);
         let addr = OsSocketAddr::from(addr);
         let res = unsafe { udt_sys::connect(u, addr.as_ptr().cast(), addr.len() as i32) };
         if res == -1 {
@@ -204,14 +292,26 @@ impl Endpoint {
         Ok(Listener { u })
     }
 
-    pub async fn connect_datagram(self: &Arc<Self>, addr: SocketAddr, rendezvous: bool) -> io::Result<Connection> {
-        let inner = self.clone();
-        let con = spawn_blocking(move || {
-            let u = inner.connect(SOCK_DGRAM, addr, rendezvous)?;
-            Ok::<_, io::Error>(Connection { u })
-        }).await.unwrap()?;
-        
-        Ok(con)
+    /// Connects to `addr`. Dropping the returned future aborts the attempt.
+    ///
+    /// UDT gives up on its own after 3 seconds (30 seconds for rendezvous).
+    pub async fn connect_datagram(&self, addr: SocketAddr, rendezvous: bool) -> io::Result<Connection> {
+        self.connect_datagram_with_options(addr, rendezvous, SocketOptions::default()).await
+    }
+
+    /// Like [`Endpoint::connect_datagram`], with per-connection `options` laid over the
+    /// endpoint's. Bind-time options are ignored.
+    pub async fn connect_datagram_with_options(&self, addr: SocketAddr, rendezvous: bool, options: SocketOptions) -> io::Result<Connection> {
+        let u = self.connect(SOCK_DGRAM, addr, rendezvous, options)?;
+        u.connected().await?;
+        Ok(Connection { u })
+    }
+
+    /// Like [`Endpoint::connect_datagram`], but gives up after `timeout` if the handshake
+    /// hasn't completed by then.
+    pub async fn connect_datagram_timeout(&self, addr: SocketAddr, rendezvous: bool, timeout: Duration) -> io::Result<Connection> {
+        time::timeout(timeout, self.connect_datagram(addr, rendezvous)).await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
     }
 }
 
@@ -254,6 +354,32 @@ impl Connection {
         self.u.peer_addr()
     }
 
+    pub fn mss(&self) -> io::Result<u32> {
+        let v = unsafe { udt_getsockopt::<i32>(self.u.inner, udt_sys::SocketOption::Mss) }?;
+        v.try_into().map_err(io::Error::other)
+    }
+
+    pub fn send_buffer_size(&self) -> io::Result<u32> {
+        let v = unsafe { udt_getsockopt::<i32>(self.u.inner, udt_sys::SocketOption::SendBuf) }?;
+        v.try_into().map_err(io::Error::other)
+    }
+
+    pub fn recv_buffer_size(&self) -> io::Result<u32> {
+        let v = unsafe { udt_getsockopt::<i32>(self.u.inner, udt_sys::SocketOption::RecvBuf) }?;
+        v.try_into().map_err(io::Error::other)
+    }
+
+    /// The sending rate cap in bytes per second, or `None` if unlimited.
+    pub fn max_bandwidth(&self) -> io::Result<Option<u64>> {
+        let v = unsafe { udt_getsockopt::<i64>(self.u.inner, udt_sys::SocketOption::MaxBandwidth) }?;
+        Ok(u64::try_from(v).ok().filter(|&v| v != 0))
+    }
+
+    /// Caps the sending rate in bytes per second. Takes effect immediately.
+    pub fn set_max_bandwidth(&self, bytes_per_sec: Option<u64>) -> io::Result<()> {
+        unsafe { udt_setsockopt(self.u.inner, udt_sys::SocketOption::MaxBandwidth, options::bandwidth(bytes_per_sec)?) }
+    }
+
     pub fn try_recv(&self, buf: &mut [u8]) -> io::Result<usize> {
         let res = unsafe { udt_sys::recvmsg(self.u.inner, buf.as_mut_ptr().cast(), buf.len().try_into().unwrap_or(i32::MAX)) };
         if res == -1 {
@@ -330,3 +456,129 @@ impl Connection {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, sync::{atomic::{AtomicUsize, Ordering}, Arc}, time::Instant};
+
+    use cc::{CcFactory, CongestionControl, Control, PacketInfo};
+
+    use super::*;
+
+    const LOOPBACK: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+    async fn pair(server: SocketOptions, client: SocketOptions) -> (Endpoint, Endpoint, Connection, Connection) {
+        let a = Endpoint::bind_with_options(LOOPBACK, server).unwrap();
+        let b = Endpoint::bind(LOOPBACK).unwrap();
+        let listener = a.listen_datagram(1).unwrap();
+        let (accepted, connected) = tokio::join!(
+            listener.accept(),
+            b.connect_datagram_with_options(a.local_addr().unwrap(), false, client)
+        );
+        (a, b, accepted.unwrap(), connected.unwrap())
+    }
+
+    #[tokio::test]
+    async fn applies_options_at_bind_and_live() {
+        // UDT keeps its buffers as packet counts, with 48 bytes of each packet going to
+        // the IP, UDP and UDT headers.
+        const PAYLOAD: u32 = 1400 - 48;
+        const DEFAULT_PAYLOAD: u32 = 1500 - 48;
+
+        let (_a, _b, accepted, connected) = pair(
+            SocketOptions::new()
+                .mss(1400)
+                .send_buffer_size(1000 * PAYLOAD)
+                .recv_buffer_size(2000 * PAYLOAD)
+                .max_bandwidth(Some(1_000_000)),
+            SocketOptions::new().send_buffer_size(3000 * DEFAULT_PAYLOAD)
+        ).await;
+
+        // Accepted connections inherit the listener's options.
+        assert_eq!(accepted.mss().unwrap(), 1400);
+        assert_eq!(accepted.send_buffer_size().unwrap(), 1000 * PAYLOAD);
+        assert_eq!(accepted.recv_buffer_size().unwrap(), 2000 * PAYLOAD);
+        assert_eq!(accepted.max_bandwidth().unwrap(), Some(1_000_000));
+        // The handshake settles on the smaller MSS. The buffers keep their packet counts,
+        // so they come back smaller in bytes.
+        assert_eq!(connected.mss().unwrap(), 1400);
+        assert_eq!(connected.send_buffer_size().unwrap(), 3000 * PAYLOAD);
+        assert_eq!(connected.max_bandwidth().unwrap(), None);
+
+        accepted.set_max_bandwidth(Some(2_000_000)).unwrap();
+        assert_eq!(accepted.max_bandwidth().unwrap(), Some(2_000_000));
+        accepted.set_max_bandwidth(Some(0)).unwrap();
+        assert_eq!(accepted.max_bandwidth().unwrap(), None);
+        connected.set_max_bandwidth(Some(500_000)).unwrap();
+        assert_eq!(connected.max_bandwidth().unwrap(), Some(500_000));
+    }
+
+    #[derive(Default)]
+    struct Calls {
+        init: AtomicUsize,
+        on_ack: AtomicUsize,
+        on_pkt_sent: AtomicUsize
+    }
+
+    /// Sends one packet every `period` microseconds, whatever the network says.
+    struct Pacer {
+        period: f64,
+        calls: Arc<Calls>
+    }
+
+    impl CongestionControl for Pacer {
+        fn init(&mut self, ctl: &mut Control<'_>) {
+            self.calls.init.fetch_add(1, Ordering::Relaxed);
+            ctl.set_pkt_snd_period(self.period);
+            ctl.set_cwnd_size(ctl.max_cwnd_size());
+        }
+
+        fn on_ack(&mut self, _ctl: &mut Control<'_>, _ackno: i32) {
+            self.calls.on_ack.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn on_pkt_sent(&mut self, _ctl: &mut Control<'_>, _pkt: PacketInfo) {
+            self.calls.on_pkt_sent.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test]
+    async fn paces_with_rust_congestion_control() {
+        const PACKETS: usize = 100;
+        const PERIOD: Duration = Duration::from_millis(5);
+
+        let calls = Arc::new(Calls::default());
+        let cc = CcFactory::new({
+            let calls = calls.clone();
+            move || Pacer { period: PERIOD.as_micros() as f64, calls: calls.clone() }
+        });
+        let (_a, _b, receiver, sender) = pair(
+            SocketOptions::new(),
+            SocketOptions::new().congestion_control(cc)
+        ).await;
+        assert_eq!(calls.init.load(Ordering::Relaxed), 1);
+
+        // Every datagram fits in a packet, so they go out a period apart. UDT makes up for
+        // packets it sent late by sending the next ones early, so only expect most of it;
+        // unpaced, loopback takes a few milliseconds.
+        let start = Instant::now();
+        let send = async {
+            for _ in 0..PACKETS {
+                sender.send(&[0; 1000]).await.unwrap();
+            }
+            sender.flush().await.unwrap();
+        };
+        let recv = async {
+            let mut buf = [0; 1000];
+            for _ in 0..PACKETS {
+                assert_eq!(receiver.recv(&mut buf).await.unwrap(), 1000);
+            }
+        };
+        tokio::join!(send, recv);
+        let elapsed = start.elapsed();
+
+        assert!(elapsed >= PERIOD * PACKETS as u32 / 2, "{PACKETS} packets took {elapsed:?}");
+        assert!(calls.on_pkt_sent.load(Ordering::Relaxed) >= PACKETS);
+        assert!(calls.on_ack.load(Ordering::Relaxed) > 0);
+    }
+}
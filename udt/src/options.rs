@@ -0,0 +1,197 @@
+use std::io;
+
+use udt_sys::{cc::CcFactory, SocketOption};
+
+use crate::util::*;
+
+/// Tunables for UDT sockets.
+///
+/// Anything left unset keeps UDT's default. The MSS, UDP buffer sizes and IPv6-only flag
+/// belong to the UDP socket that an [`Endpoint`](crate::Endpoint) multiplexes its
+/// connections over, so they only take effect at bind time. The UDT buffer sizes are per
+/// connection, and the bandwidth cap can also be changed on a live
+/// [`Connection`](crate::Connection). The congestion control algorithm is per connection,
+/// and accepted connections inherit the listener's.
+///
+/// (UDT also defines `UDT_MAXMSG`, but never implemented it. A datagram is bounded by the
+/// send buffer instead.)
+#[derive(Debug, Clone, Default)]
+pub struct SocketOptions {
+    mss: Option<u32>,
+    send_buffer_size: Option<u32>,
+    recv_buffer_size: Option<u32>,
+    udp_send_buffer_size: Option<u32>,
+    udp_recv_buffer_size: Option<u32>,
+    ipv6_only: Option<bool>,
+    max_bandwidth: Option<Option<u64>>,
+    congestion_control: Option<CcFactory>
+}
+
+impl SocketOptions {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Maximum packet size in bytes, including IP and UDP headers. Bind time only.
+    #[must_use]
+    pub fn mss(mut self, bytes: u32) -> Self {
+        self.mss = Some(bytes);
+        self
+    }
+
+    /// UDT send buffer size in bytes.
+    #[must_use]
+    pub fn send_buffer_size(mut self, bytes: u32) -> Self {
+        self.send_buffer_size = Some(bytes);
+        self
+    }
+
+    /// UDT receive buffer size in bytes. This also bounds the flow window.
+    #[must_use]
+    pub fn recv_buffer_size(mut self, bytes: u32) -> Self {
+        self.recv_buffer_size = Some(bytes);
+        self
+    }
+
+    /// Kernel send buffer size of the underlying UDP socket. Bind time only.
+    #[must_use]
+    pub fn udp_send_buffer_size(mut self, bytes: u32) -> Self {
+        self.udp_send_buffer_size = Some(bytes);
+        self
+    }
+
+    /// Kernel receive buffer size of the underlying UDP socket. Bind time only.
+    #[must_use]
+    pub fn udp_recv_buffer_size(mut self, bytes: u32) -> Self {
+        self.udp_recv_buffer_size = Some(bytes);
+        self
+    }
+
+    /// Whether an IPv6 socket refuses IPv4 peers. UDT defaults to `true`; with `false`,
+    /// binding to `[::]` gives a dual-stack socket that reaches IPv4 peers through
+    /// IPv4-mapped addresses. Bind time only.
+    #[must_use]
+    pub fn ipv6_only(mut self, only: bool) -> Self {
+        self.ipv6_only = Some(only);
+        self
+    }
+
+    /// Caps the sending rate in bytes per second. `None` means unlimited.
+    #[must_use]
+    pub fn max_bandwidth(mut self, bytes_per_sec: Option<u64>) -> Self {
+        self.max_bandwidth = Some(bytes_per_sec);
+        self
+    }
+
+    /// Replaces UDT's native congestion control with `cc`.
+    #[must_use]
+    pub fn congestion_control(mut self, cc: CcFactory) -> Self {
+        self.congestion_control = Some(cc);
+        self
+    }
+
+    /// Lays the per-connection settings of `other` over `self`. The bind-time settings of
+    /// `self` are kept, since every socket sharing a port must agree on them.
+    pub(crate) fn overlay(&self, other: &SocketOptions) -> Self {
+        let mut this = self.clone();
+        this.send_buffer_size = other.send_buffer_size.or(this.send_buffer_size);
+        this.recv_buffer_size = other.recv_buffer_size.or(this.recv_buffer_size);
+        this.max_bandwidth = other.max_bandwidth.or(this.max_bandwidth);
+        if let Some(ref cc) = other.congestion_control {
+            this.congestion_control = Some(cc.clone());
+        }
+        this
+    }
+
+    /// Must be called before the socket is bound.
+    pub(crate) fn apply(&self, u: udt_sys::Socket) -> io::Result<()> {
+        fn int(v: u32) -> io::Result<i32> {
+            v.try_into().map_err(|_| io::ErrorKind::InvalidInput.into())
+        }
+
+        if let Some(v) = self.ipv6_only {
+            unsafe { udt_setsockopt(u, SocketOption::V6Only, v) }?;
+        }
+        // The UDP buffers clamp the MSS, so they go first.
+        if let Some(v) = self.udp_send_buffer_size {
+            unsafe { udt_setsockopt(u, SocketOption::UdpSendBuf, int(v)?) }?;
+        }
+        if let Some(v) = self.udp_recv_buffer_size {
+            unsafe { udt_setsockopt(u, SocketOption::UdpRecvBuf, int(v)?) }?;
+        }
+        // The UDT buffers are counted in packets, so they go after the MSS.
+        if let Some(v) = self.mss {
+            unsafe { udt_setsockopt(u, SocketOption::Mss, int(v)?) }?;
+        }
+        if let Some(v) = self.send_buffer_size {
+            unsafe { udt_setsockopt(u, SocketOption::SendBuf, int(v)?) }?;
+        }
+        if let Some(v) = self.recv_buffer_size {
+            unsafe { udt_setsockopt(u, SocketOption::RecvBuf, int(v)?) }?;
+        }
+        if let Some(v) = self.max_bandwidth {
+            unsafe { udt_setsockopt(u, SocketOption::MaxBandwidth, bandwidth(v)?) }?;
+        }
+        if let Some(ref cc) = self.congestion_control
+            && unsafe { cc.attach(u) } == -1 {
+            return Err(unsafe { udt_getlasterror() });
+        }
+        Ok(())
+    }
+}
+
+// UDT uses -1 for unlimited, and treats 0 the same way.
+pub(crate) fn bandwidth(bytes_per_sec: Option<u64>) -> io::Result<i64> {
+    match bytes_per_sec {
+        None | Some(0) => Ok(-1),
+        Some(v) => v.try_into().map_err(|_| io::ErrorKind::InvalidInput.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlimited_bandwidth_is_minus_one() {
+        assert_eq!(bandwidth(None).unwrap(), -1);
+        assert_eq!(bandwidth(Some(0)).unwrap(), -1);
+        assert_eq!(bandwidth(Some(1_000_000)).unwrap(), 1_000_000);
+        assert_eq!(bandwidth(Some(u64::MAX)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn overlays_per_connection_settings() {
+        let base = SocketOptions::new()
+            .mss(1400)
+            .udp_send_buffer_size(1 << 20)
+            .send_buffer_size(1 << 16)
+            .recv_buffer_size(1 << 16)
+            .max_bandwidth(Some(1000))
+            .congestion_control(CcFactory::new(|| Nop));
+
+        // Unset settings fall through to the base.
+        let same = base.overlay(&SocketOptions::new());
+        assert_eq!(same.send_buffer_size, Some(1 << 16));
+        assert_eq!(same.recv_buffer_size, Some(1 << 16));
+        assert_eq!(same.max_bandwidth, Some(Some(1000)));
+        assert!(same.congestion_control.is_some());
+
+        // Set ones win, including an explicit `None` bandwidth, but bind-time ones don't.
+        let over = base.overlay(&SocketOptions::new()
+            .mss(500)
+            .udp_send_buffer_size(1 << 10)
+            .send_buffer_size(1 << 18)
+            .max_bandwidth(None));
+        assert_eq!(over.mss, Some(1400));
+        assert_eq!(over.udp_send_buffer_size, Some(1 << 20));
+        assert_eq!(over.send_buffer_size, Some(1 << 18));
+        assert_eq!(over.recv_buffer_size, Some(1 << 16));
+        assert_eq!(over.max_bandwidth, Some(None));
+    }
+
+    struct Nop;
+
+    impl udt_sys::cc::CongestionControl for Nop {}
+}
@@ -1,4 +1,4 @@
-use std::io;
+use std::{io, mem};
 
 #[allow(dead_code)]
 pub unsafe fn udt_strerror() -> String {
@@ -21,4 +21,31 @@ pub unsafe fn udt_getlasterror() -> io::Error {
             unsafe { udt_strerror() }
         )
     }
-}
\ No newline at end of file
+}
+pub unsafe fn udt_setsockopt<T: Copy>(u: udt_sys::Socket, opt: udt_sys::SocketOption, val: T) -> io::Result<()> {
+    let res = unsafe { udt_sys::setsockopt(
+        u, 0,
+        opt,
+        (&val as *const T).cast(),
+        mem::size_of::<T>() as i32
+    ) };
+    if res == -1 {
+        return Err(unsafe { udt_getlasterror() });
+    }
+    Ok(())
+}
+
+pub unsafe fn udt_getsockopt<T: Copy + Default>(u: udt_sys::Socket, opt: udt_sys::SocketOption) -> io::Result<T> {
+    let mut val = T::default();
+    let mut optlen = mem::size_of::<T>() as i32;
+    let res = unsafe { udt_sys::getsockopt(
+        u, 0,
+        opt,
+        (&mut val as *mut T).cast(),
+        &mut optlen
+    ) };
+    if res == -1 {
+        return Err(unsafe { udt_getlasterror() });
+    }
+    Ok(val)
+}
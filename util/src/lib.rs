@@ -1,6 +1,6 @@
 #![forbid(unsafe_code)]
 
-use std::net::Ipv6Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 pub mod fidpool;
 pub mod polymur;
@@ -13,4 +13,34 @@ pub fn is_unicast_global(addr: &Ipv6Addr) -> bool {
         && !addr.is_unspecified()
         && !matches!(addr.segments(), [0x2001, 0xdb8, ..] | [0x3fff, 0..=0x0fff, ..])
         && !((addr.segments()[0] == 0x2001) && (addr.segments()[1] == 0x2) && (addr.segments()[2] == 0))
-}
\ No newline at end of file
+}
+
+pub fn is_unicast_global_v4(addr: &Ipv4Addr) -> bool {
+    !addr.is_multicast()
+        && !addr.is_loopback()
+        && !addr.is_link_local()
+        && !addr.is_private()
+        && !addr.is_broadcast()
+        && !addr.is_documentation()
+        && !addr.is_unspecified()
+        && !matches!(addr.octets(), [0, ..] | [100, 64..=127, ..] | [192, 0, 0, _] | [198, 18..=19, ..] | [240..=255, ..])
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AddrScope {
+    /// Reachable from anywhere (barring firewalls)
+    Global,
+    /// Private to the local network, likely behind NAT
+    Lan
+}
+
+/// Whether `addr` is worth advertising to peers, and to whom.
+pub fn addr_scope(addr: &IpAddr) -> Option<AddrScope> {
+    match addr.to_canonical() {
+        IpAddr::V4(v4) if is_unicast_global_v4(&v4) => Some(AddrScope::Global),
+        IpAddr::V4(v4) if v4.is_private() => Some(AddrScope::Lan),
+        IpAddr::V6(v6) if is_unicast_global(&v6) => Some(AddrScope::Global),
+        IpAddr::V6(v6) if v6.is_unique_local() => Some(AddrScope::Lan),
+        _ => None
+    }
+}
@@ -18,7 +18,9 @@ pub struct PolymurHasher {
 
 impl RandomState {
     pub fn new() -> Self {
-        Self { tweak: random() }
+        // polymur-hash adds the tweak to a sum under 2^63 without wrapping, so it gets
+        // 62 bits, which can't overflow
+        Self { tweak: random::<u64>() >> 2 }
     }
 }
 